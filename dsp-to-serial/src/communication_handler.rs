@@ -1,9 +1,8 @@
-use std::{ffi::c_void, time::Duration};
+use std::time::Duration;
 
-use nix::libc::{msync, MS_INVALIDATE};
-
-use crate::{kbuf::UserWrapperBufData, msgbox::MsgboxEndpoint, sharespace::Sharespace};
+use log::{debug, trace};
 
+use crate::{error::ApplicationError, msgbox::Signal, shared_memory::SharedMemory};
 
 #[repr(C)]
 #[derive(Default, Debug)]
@@ -14,7 +13,7 @@ pub struct MsgHead {
 }
 
 impl MsgHead {
-    fn from_bytes(bytes: &[u8; 12]) -> Self {
+    pub(crate) fn from_bytes(bytes: &[u8; 12]) -> Self {
         let read_addr = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
         let write_addr = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
         let init_state = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
@@ -26,7 +25,7 @@ impl MsgHead {
         }
     }
 
-    fn to_bytes(&self) -> [u8; 12] {
+    pub(crate) fn to_bytes(&self) -> [u8; 12] {
         let mut bytes = [0u8; 12];
         bytes[0..4].copy_from_slice(&self.read_addr.to_le_bytes());
         bytes[4..8].copy_from_slice(&self.write_addr.to_le_bytes());
@@ -35,19 +34,41 @@ impl MsgHead {
     }
 }
 
-pub struct CommunicationHandler {
-    sharespace: Sharespace,
-    pub user_buf: UserWrapperBufData,
+/// Which buffer of the shared memory an end of the ring writes.
+///
+/// The ARM writes its buffer and reads the DSP's, the simulated DSP does the opposite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Arm,
+    Dsp,
+}
+
+impl Side {
+    fn peer(self) -> Side {
+        match self {
+            Side::Arm => Side::Dsp,
+            Side::Dsp => Side::Arm,
+        }
+    }
+}
+
+// Each side has a ring in its own buffer, with its head at the end of it. The local head holds
+// how far this side read the peer's ring and how far it wrote its own.
+pub struct CommunicationHandler<M: SharedMemory> {
+    pub memory: M,
+    side: Side,
     pub arm_head: MsgHead,
     dsp_head: MsgHead,
 }
 
-const SHARE_SPACE_HEAD_OFFSET: usize = 4096 - size_of::<MsgHead>();
+pub const BUFFER_LEN: usize = 4096;
+const SHARE_SPACE_HEAD_OFFSET: usize = BUFFER_LEN - size_of::<MsgHead>();
 const MIN_ADDR: usize = size_of::<MsgHead>();
 const MAX_ADDR: usize = SHARE_SPACE_HEAD_OFFSET;
 
-impl CommunicationHandler {
-    pub fn new(sharespace: Sharespace, user_buf: UserWrapperBufData) -> Self {
+impl<M: SharedMemory> CommunicationHandler<M> {
+    /// `arm_head` is the head of `side`, `dsp_head` the one of its peer.
+    pub fn new(memory: M, side: Side) -> Self {
         let mut arm_head = MsgHead::default();
         let dsp_head = MsgHead::default();
 
@@ -56,8 +77,8 @@ impl CommunicationHandler {
         arm_head.init_state = 1;
 
         let mut communication_handler = CommunicationHandler {
-            sharespace,
-            user_buf,
+            memory,
+            side,
             arm_head,
             dsp_head,
         };
@@ -67,75 +88,32 @@ impl CommunicationHandler {
         communication_handler
     }
 
-    pub fn init_no_mmap(&mut self) {
-        let mut head = MsgHead::from_bytes(
-            self.sharespace.write_buffer.as_ref()[SHARE_SPACE_HEAD_OFFSET..]
-                .try_into()
-                .unwrap(),
-        );
-
-        head.init_state = if head.init_state == 1 || head.init_state == 2 {
-            2
-        } else {
-            1
-        };
-        head.read_addr = self.user_buf.buf.pa + 4096;
-        head.write_addr = self.user_buf.buf.pa;
-
-        self.sharespace.write_buffer.as_mut()[SHARE_SPACE_HEAD_OFFSET..]
-            .copy_from_slice(&head.to_bytes())
-    }
-
-    // pVirArmBuf
-    fn get_write_slice(&mut self) -> &mut [u8] {
-        &mut self.user_buf.addr.as_mut()[0..4096]
-    }
-
-    // pVirDspBuf
-    fn get_read_slice(&self) -> &[u8] {
-        &self.user_buf.addr.as_ref()[4096..8192]
+    fn read_head(&mut self, side: Side) -> MsgHead {
+        let mut bytes = [0u8; size_of::<MsgHead>()];
+        self.memory.read(side, SHARE_SPACE_HEAD_OFFSET, &mut bytes);
+        MsgHead::from_bytes(&bytes)
     }
 
     fn read_dsp_head(&mut self) {
-        self.dsp_head = MsgHead::from_bytes(
-            self.get_read_slice()[SHARE_SPACE_HEAD_OFFSET..]
-                .try_into()
-                .unwrap(),
-        )
+        self.dsp_head = self.read_head(self.side.peer());
     }
 
+    #[allow(dead_code)] // For debugging
     fn debug_read_dsp_head(&mut self) {
-        let dsp_head = MsgHead::from_bytes(
-            self.get_read_slice()[SHARE_SPACE_HEAD_OFFSET..]
-                .try_into()
-                .unwrap(),
-        );
-        println!("DSP head in memory: {:?}", dsp_head);
+        let dsp_head = self.read_head(self.side.peer());
+        debug!("DSP head in memory: {:?}", dsp_head);
     }
 
     fn write_arm_head(&mut self) {
         let bytes = self.arm_head.to_bytes();
 
-        self.get_write_slice()[SHARE_SPACE_HEAD_OFFSET..].copy_from_slice(&bytes)
+        self.memory.write(self.side, SHARE_SPACE_HEAD_OFFSET, &bytes)
     }
 
+    #[allow(dead_code)] // For debugging
     fn debug_read_arm_head(&mut self) {
-        let arm_head = MsgHead::from_bytes(
-            self.get_write_slice()[SHARE_SPACE_HEAD_OFFSET..]
-                .try_into()
-                .unwrap(),
-        );
-        println!("ARM head in memory: {:?}", arm_head);
-    }
-
-    unsafe fn invalidate_read_buffer(&mut self) {
-        unsafe {
-            msync(
-                self.user_buf.addr.as_mut_ptr().add(4096) as *mut c_void,
-                4096,
-                MS_INVALIDATE,
-            );
-        }
+        let arm_head = self.read_head(self.side);
+        debug!("ARM head in memory: {:?}", arm_head);
     }
 
     pub fn wait_dsp_set_init(&mut self) {
@@ -144,7 +122,7 @@ impl CommunicationHandler {
         self.arm_head.init_state = 1;
 
         loop {
-            unsafe { self.invalidate_read_buffer() };
+            self.memory.invalidate(self.side.peer());
             self.read_dsp_head();
             self.write_arm_head();
 
@@ -160,28 +138,56 @@ impl CommunicationHandler {
         }
     }
 
-    pub fn dsp_mem_read(&mut self) -> Vec<u8> {
+    /// Tells the peer how far this side read and wrote.
+    fn send_signal(&self, signal: &mut impl Signal) -> Result<(), ApplicationError> {
+        let read_addr = self.arm_head.read_addr as u16 as u32;
+        let write_addr = self.arm_head.write_addr as u16 as u32;
+
+        signal.send_signal((write_addr << 16) | read_addr)
+    }
+
+    /// Takes a signal from the peer. Returns whether it wrote something new.
+    pub fn read_signal(&mut self, signal: &mut impl Signal) -> Result<bool, ApplicationError> {
+        let data_recv = signal.read_signal()?;
+        let read_addr = data_recv as u16;
+        let write_addr = (data_recv >> 16) as u16;
+
+        trace!(
+            "Msgbox read signal: read {}, write {}",
+            read_addr, write_addr
+        );
+
+        if write_addr >= 5000 {
+            return Ok(false);
+        }
+
+        Ok(write_addr != self.arm_head.read_addr as u16)
+    }
+
+    /// Reads everything the DSP wrote since the last read. Fails if the DSP's head is corrupt.
+    pub fn dsp_mem_read(&mut self) -> Result<Vec<u8>, ApplicationError> {
         self.read_dsp_head();
 
+        if !(MIN_ADDR..MAX_ADDR).contains(&(self.dsp_head.write_addr as usize)) {
+            return Err(ApplicationError::InvalidHead(self.dsp_head.write_addr));
+        }
+
         if self.arm_head.read_addr == self.dsp_head.write_addr {
-            return vec![];
+            return Ok(vec![]);
         }
 
         let mut msg_start_addr: usize = self.arm_head.read_addr as usize;
-        let msg_size: usize;
-
-        if self.arm_head.read_addr < self.dsp_head.write_addr {
-            msg_size = (self.dsp_head.write_addr - self.arm_head.read_addr) as usize;
+        let msg_size = if self.arm_head.read_addr < self.dsp_head.write_addr {
+            (self.dsp_head.write_addr - self.arm_head.read_addr) as usize
         } else {
-            msg_size = MAX_ADDR
-                - MIN_ADDR
-                - ((self.arm_head.read_addr - self.dsp_head.write_addr) as usize);
-        }
+            MAX_ADDR - MIN_ADDR - ((self.arm_head.read_addr - self.dsp_head.write_addr) as usize)
+        };
 
-        let mut result;
+        let peer = self.side.peer();
+        let mut result = vec![0u8; msg_size];
 
         if msg_start_addr + msg_size <= MAX_ADDR {
-            result = self.get_read_slice()[msg_start_addr..msg_start_addr + msg_size].to_vec();
+            self.memory.read(peer, msg_start_addr, &mut result);
 
             msg_start_addr += msg_size;
 
@@ -190,71 +196,271 @@ impl CommunicationHandler {
             }
         } else {
             let len1 = MAX_ADDR - msg_start_addr;
-            result = self.get_read_slice()[msg_start_addr..msg_start_addr + len1].to_vec();
-            result.extend(self.get_read_slice()[MIN_ADDR..MIN_ADDR + msg_size - len1].to_vec());
+            self.memory.read(peer, msg_start_addr, &mut result[..len1]);
+            self.memory.read(peer, MIN_ADDR, &mut result[len1..]);
             msg_start_addr = MIN_ADDR + msg_size - len1;
         }
 
         if msg_size > 0 {
             self.arm_head.read_addr = msg_start_addr as u32;
+            // The DSP only learns how far we read from our head in shared memory. Without
+            // publishing it here it would only be updated with our next write, and a DSP
+            // sending more than fits in its ring stalls while the serial side is quiet.
+            self.write_arm_head();
         }
 
-        return result;
+        Ok(result)
     }
 
-    pub fn dsp_mem_write(&mut self, msgbox_endpoint: &mut MsgboxEndpoint, data: &[u8]) {
-        let mut len = data.len();
+    /// Bytes that can be written before the ring is full. One byte always stays free, otherwise a
+    /// full ring would look empty.
+    fn free_space(&self) -> Result<usize, ApplicationError> {
+        let read_addr = self.dsp_head.read_addr as usize;
+        let write_addr = self.arm_head.write_addr as usize;
 
-        if len > 4000 || len <= 0 {
-            panic!("Cannot send too much or nothing!");
+        if !(MIN_ADDR..MAX_ADDR).contains(&read_addr) {
+            return Err(ApplicationError::InvalidHead(self.dsp_head.read_addr));
         }
 
+        let used = if read_addr <= write_addr {
+            write_addr - read_addr
+        } else {
+            MAX_ADDR - MIN_ADDR - (read_addr - write_addr)
+        };
+
+        Ok(MAX_ADDR - MIN_ADDR - used - 1)
+    }
+
+    /// Writes as much of `data` as fits in the ring and signals the DSP.
+    ///
+    /// Returns the number of bytes written, 0 while the ring is full. The rest has to be written
+    /// again once the DSP advanced its read address. Nothing is written if signalling fails.
+    pub fn dsp_mem_write(
+        &mut self,
+        signal: &mut impl Signal,
+        data: &[u8],
+    ) -> Result<usize, ApplicationError> {
         // Check: Can we not get the dsp head here?
         //self.debug_read_dsp_head();
         //self.dsp_head.read_addr = msgbox_endpoint.msgbox_new_msg_read as u32;
         self.read_dsp_head();
-        println!("Local DSP head: {:?}", self.dsp_head);
-        let free_size;
-
-        if self.dsp_head.read_addr <= self.arm_head.write_addr {
-            free_size =
-                MAX_ADDR - MIN_ADDR - (self.arm_head.write_addr - self.dsp_head.read_addr) as usize;
-            if free_size <= len {
-                panic!("Good job");
-            }
-        } else {
-            free_size = (self.dsp_head.read_addr - self.arm_head.write_addr) as usize;
-            if free_size <= len {
-                panic!("Good job");
-            }
+        trace!("Local DSP head: {:?}", self.dsp_head);
+
+        let len = data.len().min(self.free_space()?);
+
+        if len == 0 {
+            return Ok(0);
         }
 
-        let mut pmsg = self.arm_head.write_addr as usize;
+        let data = &data[..len];
+        let old_write_addr = self.arm_head.write_addr;
+        let mut pmsg = old_write_addr as usize;
+
         if pmsg + len <= MAX_ADDR {
-            self.get_write_slice()[pmsg..pmsg + len].copy_from_slice(data);
+            self.memory.write(self.side, pmsg, data);
             pmsg += len;
             if pmsg >= MAX_ADDR {
                 pmsg = MIN_ADDR;
             }
         } else {
-            let len1 = MAX_ADDR - self.arm_head.write_addr as usize;
-            self.get_write_slice()[pmsg..pmsg + len1].copy_from_slice(&data[..len1]);
-            len -= len1;
-            self.get_write_slice()[MIN_ADDR..MIN_ADDR + len].copy_from_slice(&data[len1..]);
-            pmsg = MIN_ADDR + len;
+            let len1 = MAX_ADDR - pmsg;
+            self.memory.write(self.side, pmsg, &data[..len1]);
+            self.memory.write(self.side, MIN_ADDR, &data[len1..]);
+            pmsg = MIN_ADDR + len - len1;
         }
 
         self.arm_head.write_addr = pmsg as u32;
         self.arm_head.init_state = 1;
         self.write_arm_head();
 
-        msgbox_endpoint
-            .msgbox_send_signal(
-                self.arm_head.read_addr as u16,
-                self.arm_head.write_addr as u16,
-            )
-            .unwrap();
+        let signalled = self.send_signal(signal);
+
+        // The DSP only reads what it was told about, so the data can be written again
+        if let Err(e) = signalled {
+            self.arm_head.write_addr = old_write_addr;
+            self.write_arm_head();
+            return Err(e);
+        }
+
+        trace!("New write addr: {}", self.arm_head.write_addr);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Instant};
+
+    use super::*;
+    use crate::simulated::{SimulatedMemory, SimulatedSignal, spawn_echo_dsp};
+
+    // Bytes the ring holds at most
+    const CAPACITY: usize = MAX_ADDR - MIN_ADDR - 1;
 
-        println!("New write addr: {}", self.arm_head.write_addr);
+    struct Ring {
+        memory: SimulatedMemory,
+        arm: CommunicationHandler<SimulatedMemory>,
+        arm_signal: SimulatedSignal,
+        dsp: CommunicationHandler<SimulatedMemory>,
+        dsp_signal: SimulatedSignal,
     }
-}
\ No newline at end of file
+
+    impl Ring {
+        fn new() -> Self {
+            let memory = SimulatedMemory::new();
+            let (arm_signal, dsp_signal) = SimulatedSignal::pair().unwrap();
+
+            Ring {
+                arm: CommunicationHandler::new(memory.clone(), Side::Arm),
+                dsp: CommunicationHandler::new(memory.clone(), Side::Dsp),
+                memory,
+                arm_signal,
+                dsp_signal,
+            }
+        }
+
+        fn write(&mut self, data: &[u8]) -> usize {
+            self.arm.dsp_mem_write(&mut self.arm_signal, data).unwrap()
+        }
+
+        // Reads on the DSP side what the ARM signalled
+        fn read(&mut self) -> Vec<u8> {
+            let mut signalled = false;
+
+            while self.dsp_signal.has_signal() {
+                signalled |= self.dsp.read_signal(&mut self.dsp_signal).unwrap();
+            }
+
+            assert!(signalled);
+            self.dsp.dsp_mem_read().unwrap()
+        }
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut ring = Ring::new();
+
+        assert_eq!(ring.write(b"hello"), 5);
+        assert_eq!(ring.read(), b"hello");
+        assert!(ring.dsp.dsp_mem_read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn full_ring_takes_nothing() {
+        let mut ring = Ring::new();
+        let data = pattern(CAPACITY + 100, 1);
+
+        assert_eq!(ring.write(&data), CAPACITY);
+        assert_eq!(ring.write(&data[CAPACITY..]), 0);
+
+        // Reading makes room again
+        assert_eq!(ring.read(), data[..CAPACITY]);
+        assert_eq!(ring.write(&data[CAPACITY..]), 100);
+        assert_eq!(ring.read(), data[CAPACITY..]);
+    }
+
+    #[test]
+    fn partial_writes_continue_where_they_stopped() {
+        let mut ring = Ring::new();
+        let data = pattern(3 * CAPACITY, 2);
+        let mut written = 0;
+        let mut received = vec![];
+        let mut partial = false;
+
+        while written < data.len() {
+            let chunk = &data[written..(written + 1000).min(data.len())];
+            let len = ring.write(chunk);
+            written += len;
+
+            // The ring is full until the DSP catches up
+            if len < chunk.len() {
+                partial |= len > 0;
+                received.extend(ring.read());
+            }
+        }
+
+        received.extend(ring.read());
+        assert!(partial);
+        assert!(received == data);
+    }
+
+    #[test]
+    fn write_and_read_wrap_around() {
+        let mut ring = Ring::new();
+
+        let first = pattern(3000, 3);
+        assert_eq!(ring.write(&first), first.len());
+        assert_eq!(ring.read(), first);
+
+        // Crosses the end of the ring
+        let second = pattern(2000, 4);
+        assert_eq!(ring.write(&second), second.len());
+        assert!((ring.arm.arm_head.write_addr as usize) < MIN_ADDR + 3000);
+        assert_eq!(ring.read(), second);
+        assert_eq!(ring.dsp.arm_head.read_addr, ring.arm.arm_head.write_addr);
+    }
+
+    #[test]
+    fn corrupted_head_is_rejected() {
+        let mut ring = Ring::new();
+        let head = MsgHead {
+            read_addr: 5000,
+            write_addr: MIN_ADDR as u32,
+            init_state: 1,
+        };
+        ring.memory
+            .write(Side::Dsp, SHARE_SPACE_HEAD_OFFSET, &head.to_bytes());
+
+        let result = ring.arm.dsp_mem_write(&mut ring.arm_signal, b"hello");
+
+        assert!(matches!(result, Err(ApplicationError::InvalidHead(5000))));
+        assert_eq!(ring.arm.arm_head.write_addr, MIN_ADDR as u32);
+    }
+
+    #[test]
+    fn corrupted_write_addr_is_rejected() {
+        let mut ring = Ring::new();
+        let head = MsgHead {
+            read_addr: MIN_ADDR as u32,
+            write_addr: 5000,
+            init_state: 1,
+        };
+        ring.memory
+            .write(Side::Dsp, SHARE_SPACE_HEAD_OFFSET, &head.to_bytes());
+
+        let result = ring.arm.dsp_mem_read();
+
+        assert!(matches!(result, Err(ApplicationError::InvalidHead(5000))));
+        assert_eq!(ring.arm.arm_head.read_addr, MIN_ADDR as u32);
+    }
+
+    #[test]
+    fn echo_dsp_sends_everything_back() {
+        let (memory, mut signal) = spawn_echo_dsp().unwrap();
+        let mut arm = CommunicationHandler::new(memory, Side::Arm);
+        arm.wait_dsp_set_init();
+
+        let data = pattern(10_000, 5);
+        let mut written = 0;
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while received.len() < data.len() && Instant::now() < deadline {
+            if written < data.len() {
+                written += arm.dsp_mem_write(&mut signal, &data[written..]).unwrap();
+            }
+
+            if !signal.has_signal() {
+                thread::sleep(Duration::from_millis(1));
+            } else if arm.read_signal(&mut signal).unwrap() {
+                received.extend(arm.dsp_mem_read().unwrap());
+            }
+        }
+
+        assert!(received == data);
+    }
+}
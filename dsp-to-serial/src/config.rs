@@ -0,0 +1,229 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, Parser};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::communication_handler::BUFFER_LEN;
+
+// Defaults of the msgbox demo firmware
+const DEFAULT_KBUF_NAME: &str = "test";
+const DEFAULT_KBUF_SIZE: u32 = 4 * 4096;
+const DEFAULT_ENDPOINT_NAME: &str = "msgbox_demo";
+const DEFAULT_ENDPOINT_SRC: u32 = 0x3;
+// RPMSG_ADDR_ANY
+const DEFAULT_ENDPOINT_DST: u32 = 0xffffffff;
+const DEFAULT_DSP_DEBUG_DEVICE: &str = "/dev/dsp_debug";
+const DEFAULT_KBUF_MGR_DEVICE: &str = "/dev/kbuf-mgr-0";
+const DEFAULT_RPMSG_CTRL_DEVICE: &str = "/dev/rpmsg_ctrl0";
+
+// Names are passed to the kernel in 32 byte fields ending with a NUL
+pub const MAX_NAME_LEN: usize = 31;
+
+#[derive(Parser, Debug)]
+#[command(name = "dsp-to-serial", about = "Bridge the DSP's shared memory ring to a virtual serial device", version = "0.1")]
+pub struct Args {
+    /// TOML file with any of the link, kbuf, endpoint and device settings below, named with
+    /// underscores (e.g. `kbuf_name = "test"`). Flags override it.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Where the virtual serial device is linked [default: <temp dir>/dsp-serial]
+    #[arg(long)]
+    pub link_path: Option<PathBuf>,
+
+    /// Name of the kbuf shared with the DSP [default: test]
+    #[arg(long)]
+    pub kbuf_name: Option<String>,
+
+    /// Size of the kbuf in bytes [default: 16384]
+    #[arg(long, value_parser = parse_u32)]
+    pub kbuf_size: Option<u32>,
+
+    /// Name of the rpmsg endpoint the DSP firmware signals on [default: msgbox_demo]
+    #[arg(long)]
+    pub endpoint_name: Option<String>,
+
+    /// Local address of the rpmsg endpoint, decimal or hex [default: 0x3]
+    #[arg(long, value_parser = parse_u32)]
+    pub endpoint_src: Option<u32>,
+
+    /// Remote address of the rpmsg endpoint, decimal or hex [default: 0xffffffff, any address]
+    #[arg(long, value_parser = parse_u32)]
+    pub endpoint_dst: Option<u32>,
+
+    /// [default: /dev/dsp_debug]
+    #[arg(long)]
+    pub dsp_debug_device: Option<PathBuf>,
+
+    /// [default: /dev/kbuf-mgr-0]
+    #[arg(long)]
+    pub kbuf_mgr_device: Option<PathBuf>,
+
+    /// [default: /dev/rpmsg_ctrl0]
+    #[arg(long)]
+    pub rpmsg_ctrl_device: Option<PathBuf>,
+
+    /// Talk to a DSP simulated in process, which echoes everything back. Only the link path is used.
+    #[arg(long, default_value_t = false)]
+    pub simulate: bool,
+
+    /// Log more, -vv logs every transfer
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log less, -q only logs warnings and -qq only errors
+    #[arg(short, long, action = ArgAction::Count)]
+    pub quiet: u8,
+
+    /// Log to syslog instead of stderr
+    #[arg(long, default_value_t = false)]
+    pub syslog: bool,
+}
+
+// Accepts 0x prefixed hex as well, since endpoint addresses are usually written that way
+fn parse_u32(value: &str) -> Result<u32, String> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    parsed.map_err(|e| e.to_string())
+}
+
+// The settings of Args, as read from --config
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    link_path: Option<PathBuf>,
+    kbuf_name: Option<String>,
+    kbuf_size: Option<u32>,
+    endpoint_name: Option<String>,
+    endpoint_src: Option<u32>,
+    endpoint_dst: Option<u32>,
+    dsp_debug_device: Option<PathBuf>,
+    kbuf_mgr_device: Option<PathBuf>,
+    rpmsg_ctrl_device: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("{setting} {name:?} is longer than {MAX_NAME_LEN} bytes")]
+    NameTooLong { setting: &'static str, name: String },
+    #[error("kbuf_size {0} is too small, the ring needs at least {min} bytes", min = 2 * BUFFER_LEN)]
+    KbufTooSmall(u32),
+}
+
+/// Settings from the command line, the config file and the defaults, in that order.
+#[derive(Debug)]
+pub struct Settings {
+    pub link_path: PathBuf,
+    pub kbuf_name: String,
+    pub kbuf_size: u32,
+    pub endpoint_name: String,
+    pub endpoint_src: u32,
+    pub endpoint_dst: u32,
+    pub dsp_debug_device: PathBuf,
+    pub kbuf_mgr_device: PathBuf,
+    pub rpmsg_ctrl_device: PathBuf,
+    pub simulate: bool,
+    /// 0 logs at info level, more is more verbose
+    pub verbosity: i8,
+    pub syslog: bool,
+}
+
+fn read_file_config(path: &Path) -> Result<FileConfig, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    toml::from_str(&text).map_err(|source| ConfigError::Parse {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+fn check_name(setting: &'static str, name: &str) -> Result<(), ConfigError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(ConfigError::NameTooLong {
+            setting,
+            name: name.to_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+impl Settings {
+    pub fn load(args: Args) -> Result<Settings, ConfigError> {
+        let file = match &args.config {
+            Some(path) => read_file_config(path)?,
+            None => FileConfig::default(),
+        };
+
+        let settings = Settings {
+            link_path: args
+                .link_path
+                .or(file.link_path)
+                .unwrap_or_else(|| std::env::temp_dir().join("dsp-serial")),
+            kbuf_name: args
+                .kbuf_name
+                .or(file.kbuf_name)
+                .unwrap_or_else(|| DEFAULT_KBUF_NAME.to_owned()),
+            kbuf_size: args.kbuf_size.or(file.kbuf_size).unwrap_or(DEFAULT_KBUF_SIZE),
+            endpoint_name: args
+                .endpoint_name
+                .or(file.endpoint_name)
+                .unwrap_or_else(|| DEFAULT_ENDPOINT_NAME.to_owned()),
+            endpoint_src: args
+                .endpoint_src
+                .or(file.endpoint_src)
+                .unwrap_or(DEFAULT_ENDPOINT_SRC),
+            endpoint_dst: args
+                .endpoint_dst
+                .or(file.endpoint_dst)
+                .unwrap_or(DEFAULT_ENDPOINT_DST),
+            dsp_debug_device: args
+                .dsp_debug_device
+                .or(file.dsp_debug_device)
+                .unwrap_or_else(|| DEFAULT_DSP_DEBUG_DEVICE.into()),
+            kbuf_mgr_device: args
+                .kbuf_mgr_device
+                .or(file.kbuf_mgr_device)
+                .unwrap_or_else(|| DEFAULT_KBUF_MGR_DEVICE.into()),
+            rpmsg_ctrl_device: args
+                .rpmsg_ctrl_device
+                .or(file.rpmsg_ctrl_device)
+                .unwrap_or_else(|| DEFAULT_RPMSG_CTRL_DEVICE.into()),
+            simulate: args.simulate,
+            // At most one of them is set
+            verbosity: i8::try_from(args.verbose).unwrap_or(i8::MAX)
+                - i8::try_from(args.quiet).unwrap_or(i8::MAX),
+            syslog: args.syslog,
+        };
+
+        check_name("kbuf_name", &settings.kbuf_name)?;
+        check_name("endpoint_name", &settings.endpoint_name)?;
+
+        // Both buffers of the ring are in the kbuf
+        if (settings.kbuf_size as usize) < 2 * BUFFER_LEN {
+            return Err(ConfigError::KbufTooSmall(settings.kbuf_size));
+        }
+
+        Ok(settings)
+    }
+}
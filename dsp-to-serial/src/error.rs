@@ -7,6 +7,8 @@ pub enum ApplicationError {
     RawFileIoError(#[from] Errno),
     #[error("File I/O Error (Managed)")]
     ManagedFileIoError(#[from] std::io::Error),
+    #[error("DSP head address {0} is outside the shared buffer")]
+    InvalidHead(u32),
     #[error("Unknown error")]
     UnknownError(&'static str),
 }
@@ -1,9 +1,11 @@
-use std::os::fd::{AsRawFd, OwnedFd};
+use std::{os::fd::{AsRawFd, OwnedFd}, path::Path};
 
 use memmap2::{MmapMut, MmapOptions};
 use nix::{fcntl::{open, OFlag}, ioctl_readwrite_bad, sys::stat::Mode};
 
-use crate::{error::ApplicationError, util::{u8_slice_to_string, wrap_ioctl_negative_invalid}};
+use log::debug;
+
+use crate::{error::ApplicationError, util::{string_to_u8_array, u8_slice_to_string, wrap_ioctl_negative_invalid}};
 
 
 ioctl_readwrite_bad!(kbuf_mgr_dev_create_buf, 0x100, KBufBufData);
@@ -23,26 +25,24 @@ pub struct KBufBufData {
 pub struct UserWrapperBufData {
     pub buf: KBufBufData,
     mgr_fd: OwnedFd,
+    #[allow(dead_code)] // Kept open for as long as the buffer is mapped
     map_fd: OwnedFd,
     pub addr: MmapMut,
 }
 
 impl Drop for UserWrapperBufData {
     fn drop(&mut self) {
-        println!("Dropping UserWrapperBufData, cleaning up kbuf");
+        debug!("Dropping UserWrapperBufData, cleaning up kbuf");
         let mgr_fd_raw = self.mgr_fd.as_raw_fd();
         let _ = unsafe { kbuf_mgr_dev_destroy_buf(mgr_fd_raw, &mut self.buf) };
     }
 }
 
-impl Default for KBufBufData {
-    fn default() -> Self {
-        let mut buf = [0u8; 32];
-        buf[..4].copy_from_slice(b"test");
-
+impl KBufBufData {
+    fn new(name: &str, len: u32) -> Self {
         Self {
-            name: buf,
-            len: 4 * 4096,
+            name: string_to_u8_array(name),
+            len,
             ktype: 1, // KBUF_TYPE_NONCACHE,
             minor: Default::default(),
             va: Default::default(),
@@ -51,20 +51,27 @@ impl Default for KBufBufData {
     }
 }
 
-pub fn kbuf_use_new_buf(arm_write_addr: u32) -> Result<UserWrapperBufData, ApplicationError> {
-    let mut buf_data = KBufBufData::default(); // Maybe should be dynamic?
-    buf_data.pa = arm_write_addr;
+pub fn kbuf_use_new_buf(
+    mgr_device: &Path,
+    name: &str,
+    len: u32,
+    arm_write_addr: u32,
+) -> Result<UserWrapperBufData, ApplicationError> {
+    let mut buf_data = KBufBufData {
+        pa: arm_write_addr,
+        ..KBufBufData::new(name, len)
+    };
 
-    let mgr_fd = open("/dev/kbuf-mgr-0", OFlag::O_RDWR, Mode::empty())?;
+    let mgr_fd = open(mgr_device, OFlag::O_RDWR, Mode::empty())?;
     let mgr_fd_raw = mgr_fd.as_raw_fd();
 
-    println!("{:#?}", &buf_data);
+    debug!("{:#?}", &buf_data);
 
     unsafe { wrap_ioctl_negative_invalid(kbuf_mgr_dev_create_buf(mgr_fd_raw, &mut buf_data))? };
 
     let map_dev_path = format!("/dev/kbuf-map-{}-{}", buf_data.minor, u8_slice_to_string(&buf_data.name));
 
-    println!("Mapping kbuf device at path: {}", map_dev_path);
+    debug!("Mapping kbuf device at path: {}", map_dev_path);
 
     let map_fd = open(map_dev_path.as_str(), OFlag::O_RDWR, Mode::empty())?;
 
@@ -0,0 +1,78 @@
+use std::{
+    ffi::CString,
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use nix::libc;
+
+// openlog keeps the pointer, so the identity has to live as long as the process
+const SYSLOG_IDENT: &std::ffi::CStr = c"dsp-to-serial";
+
+/// Writes log records to stderr, or to syslog once `syslog` is set.
+struct Logger {
+    syslog: AtomicBool,
+}
+
+static LOGGER: Logger = Logger {
+    syslog: AtomicBool::new(false),
+};
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        if self.syslog.load(Ordering::Relaxed) {
+            let priority = match record.level() {
+                Level::Error => libc::LOG_ERR,
+                Level::Warn => libc::LOG_WARNING,
+                Level::Info => libc::LOG_INFO,
+                Level::Debug | Level::Trace => libc::LOG_DEBUG,
+            };
+
+            // Messages with a NUL in them are cut off there
+            let message = record.args().to_string();
+            let message = CString::new(message.split('\0').next().unwrap_or_default()).unwrap();
+
+            // SAFETY: The format takes exactly the one string passed
+            unsafe { libc::syslog(priority, c"%s".as_ptr(), message.as_ptr()) };
+        } else {
+            let _ = writeln!(
+                std::io::stderr().lock(),
+                "{:<5} {}",
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger. `verbosity` is 0 for info, positive for debug and trace, negative for
+/// warnings and errors only.
+pub fn init(verbosity: i8, syslog: bool) {
+    let level = match verbosity {
+        ..=-2 => LevelFilter::Error,
+        -1 => LevelFilter::Warn,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        2.. => LevelFilter::Trace,
+    };
+
+    if syslog {
+        // SAFETY: The identity is static
+        unsafe { libc::openlog(SYSLOG_IDENT.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+        LOGGER.syslog.store(true, Ordering::Relaxed);
+    }
+
+    log::set_logger(&LOGGER).expect("Logger installed twice");
+    log::set_max_level(level);
+}
@@ -1,105 +1,316 @@
 use std::{
-    fs::remove_file, io::{ErrorKind, Read, Write}, os::unix::fs::symlink, time::{Duration, Instant}
+    fmt::Display, fs::remove_file, io::{ErrorKind, Read, Write}, os::{fd::{AsRawFd, BorrowedFd}, unix::fs::symlink}, path::Path, process::exit, time::{Duration, Instant}
 };
 
+use clap::Parser;
+use log::{debug, error, info, trace, warn};
+use nix::{errno::Errno, poll::{poll, PollFd, PollFlags, PollTimeout}};
 use serialport::{SerialPort, TTYPort};
 
 use crate::{
-    communication_handler::CommunicationHandler, kbuf::{kbuf_use_new_buf}, msgbox::MsgboxEndpoint, sharespace::{sharespace_mmap}
+    communication_handler::{CommunicationHandler, Side}, config::{Args, Settings}, kbuf::{kbuf_use_new_buf}, msgbox::{MsgboxEndpoint, Signal}, shared_memory::{DspMemory, SharedMemory}, sharespace::{sharespace_mmap}
 };
 
+mod config;
 mod error;
 mod kbuf;
+mod logger;
 mod msgbox;
 mod sharespace;
+mod shared_memory;
+mod simulated;
 mod util;
 mod communication_handler;
 
-fn read_dsp(msgbox : &mut MsgboxEndpoint, handler : &mut CommunicationHandler, port: &mut TTYPort) {
-    if !msgbox.msgbox_has_signal()
+// How often writing to a full ring is retried
+const RETRY_INTERVAL: Duration = Duration::from_millis(2);
+// Retries after an error back off up to this
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// Data from the serial port is dropped once the DSP failed to take it this many times in a row
+const MAX_WRITE_FAILURES: u32 = 20;
+
+// When to try the DSP or the serial port again. Errors are logged when they change, so a
+// persistent one doesn't flood the log.
+#[derive(Default)]
+struct Retry {
+    failures: u32,
+    last_error: Option<String>,
+    at: Option<Instant>,
+}
+
+impl Retry {
+    fn after(&mut self, interval: Duration) {
+        self.at = Some(Instant::now() + interval);
+    }
+
+    // Returns how many times in a row it failed
+    fn failed(&mut self, what: &str, error: &dyn Display) -> u32 {
+        let message = error.to_string();
+
+        if self.last_error.as_ref() != Some(&message) {
+            warn!("{}, retrying: {}", what, message);
+            self.last_error = Some(message);
+        }
+
+        self.failures += 1;
+        self.after(RETRY_INTERVAL.saturating_mul(1 << self.failures.min(16)).min(MAX_RETRY_INTERVAL));
+        self.failures
+    }
+
+    fn succeeded(&mut self) {
+        if self.failures > 0 {
+            info!("Recovered after {} failed attempts", self.failures);
+        }
+
+        *self = Retry::default();
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.at.is_none_or(|at| at <= now)
+    }
+}
+
+// Data from the DSP waits in `unwritten` while the pty is full. The DSP is not read meanwhile, so
+// the rest stays in its ring until the client catches up.
+fn read_dsp(msgbox : &mut impl Signal, handler : &mut CommunicationHandler<impl SharedMemory>, port: &mut TTYPort, unwritten: &mut Vec<u8>) {
+    if !unwritten.is_empty() {
+        write_port(port, unwritten);
+        return;
+    }
+
+    if !msgbox.has_signal()
     {
         return;
     }
 
     let now = Instant::now();
 
-    let new_data_to_read = match msgbox
-        .msgbox_read_signal(handler.arm_head.read_addr as u16) {
+    let new_data_to_read = match handler.read_signal(msgbox) {
             Ok(n) => n,
             Err(e) => {
-                println!("Failed to read signal from msgbox: {}", e);
+                warn!("Failed to read signal from msgbox: {}", e);
                 return;
             }
         };
 
     if !new_data_to_read
     {
-        println!("Got msgbox message but no data to read?");
+        trace!("Got msgbox message but no data to read?");
         return;
     }
 
-    let data = handler.dsp_mem_read();
+    let data = match handler.dsp_mem_read() {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to read from the DSP: {}", e);
+                return;
+            }
+        };
 
-    if data.len() <= 0
+    if data.is_empty()
     {
-        println!("No data available to read...");
+        trace!("No data available to read...");
         return;
     }
 
-    
-    port.write_all(&data).unwrap(); // TODO: Erorr handling
-    println!("Read {} bytes from the DSP in {}ms.", data.len(), now.elapsed().as_millis());
+    trace!("Read {} bytes from the DSP in {}ms.", data.len(), now.elapsed().as_millis());
+
+    unwritten.extend_from_slice(&data);
+    write_port(port, unwritten);
+}
+
+// Writes as much of `unwritten` as the pty takes without blocking
+fn write_port(port: &mut TTYPort, unwritten: &mut Vec<u8>) {
+    while !unwritten.is_empty() {
+        match port.write(unwritten) {
+            Ok(len) => {
+                unwritten.drain(..len);
+            }
+            // The pty is full until the client reads
+            Err(ref e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                return;
+            }
+            // The data is gone from the ring already, so there is nothing to retry
+            Err(e) => {
+                warn!("Failed to write {} bytes from the DSP to the serial port: {}", unwritten.len(), e);
+                unwritten.clear();
+                return;
+            }
+        }
+    }
 }
 
-fn write_dsp(msgbox : &mut MsgboxEndpoint, handler : &mut CommunicationHandler, port: &mut TTYPort)
+// Data read from the serial port waits in `pending` until the DSP has room for it. The port is not
+// read meanwhile, so a busy DSP holds up the writer instead of losing its data.
+fn write_dsp(msgbox : &mut impl Signal, handler : &mut CommunicationHandler<impl SharedMemory>, port: &mut TTYPort, pending: &mut Vec<u8>, port_retry: &mut Retry, dsp_retry: &mut Retry)
 {
     let now = Instant::now();
-    let mut buff = [0u8; 4096];
-    let len = match port.read(&mut buff) {
-        Ok(l) => l,
-        Err(ref e) if e.kind() == ErrorKind::TimedOut => {
+
+    if pending.is_empty() {
+        if !port_retry.is_due(now) {
             return;
         }
+
+        let mut buff = [0u8; 4096];
+        let len = match port.read(&mut buff) {
+            Ok(l) => l,
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => {
+                return;
+            }
+            Err(e) => {
+                port_retry.failed("Failed to read from the serial port", &e);
+                return;
+            }
+        };
+
+        port_retry.succeeded();
+        pending.extend_from_slice(&buff[..len]);
+    } else if !dsp_retry.is_due(now) {
+        return;
+    }
+
+    let len = match handler.dsp_mem_write(msgbox, pending) {
+        Ok(len) => len,
         Err(e) => {
-            println!("Error reading from serial port: {}", e);
-            panic!();
+            let failures = dsp_retry.failed("Failed to write to the DSP", &e);
+
+            if failures >= MAX_WRITE_FAILURES {
+                if failures == MAX_WRITE_FAILURES {
+                    error!("The DSP keeps failing, dropping data from the serial port until it recovers");
+                }
+
+                pending.clear();
+            }
+
+            return;
+        }
+    };
+
+    dsp_retry.succeeded();
+    pending.drain(..len);
+
+    // The ring is full until the DSP advances its read address
+    if !pending.is_empty() {
+        dsp_retry.after(RETRY_INTERVAL);
+    }
+
+    if len > 0 {
+        trace!("Wrote {} bytes to the DSP in {}ms.", len, now.elapsed().as_millis());
+    }
+}
+
+// Sleeps until the DSP signals or the serial port has data. While data waits for room in the ring
+// or a retry after an error, the port is not watched and the loop wakes up for the retry instead.
+// While data from the DSP waits for room in the pty, the loop wakes up once the pty takes more
+// instead of when the DSP signals.
+fn wait_for_events(msgbox : &impl Signal, port: &TTYPort, pending: &[u8], unwritten: &[u8], port_retry: &Retry, dsp_retry: &Retry) -> Result<(), Errno> {
+    // SAFETY: The port is borrowed for as long as the descriptor is used
+    let port_fd = unsafe { BorrowedFd::borrow_raw(port.as_raw_fd()) };
+    let mut fds = if unwritten.is_empty() {
+        vec![PollFd::new(msgbox.as_fd(), PollFlags::POLLIN)]
+    } else {
+        vec![PollFd::new(port_fd, PollFlags::POLLOUT)]
+    };
+
+    let retry_at = if pending.is_empty() {
+        port_retry.at
+    } else {
+        Some(dsp_retry.at.unwrap_or_else(Instant::now))
+    };
+
+    let timeout = match retry_at {
+        None => {
+            fds.push(PollFd::new(port_fd, PollFlags::POLLIN));
+            PollTimeout::NONE
+        }
+        // Rounded up, waking up early would only mean waiting again
+        Some(at) => {
+            let millis = at.saturating_duration_since(Instant::now()).as_micros().div_ceil(1000);
+            PollTimeout::try_from(millis).unwrap_or(PollTimeout::MAX)
         }
     };
 
-    handler.dsp_mem_write(msgbox, &buff[..len]);
-    println!("Wrote {} bytes to the DSP in {}ms.", len, now.elapsed().as_millis());
+    match poll(&mut fds, timeout) {
+        Ok(_) | Err(Errno::EINTR) => Ok(()),
+        Err(e) => Err(e),
+    }
 }
 
 fn main() {
-    println!("Hello, world!");
-    let mmap = sharespace_mmap();
-    println!("Got sharespace mmap!");
-    let kbuf = kbuf_use_new_buf(mmap.dsp_sharespace.arm_write_addr).unwrap();
-    println!("Got kbuf mmap!");
-    let mut handler = CommunicationHandler::new(mmap, kbuf);
-    println!("Got communication handler!");
-    handler.init_no_mmap();
-    println!("Done init_no_mmap!");
+    let settings = match Settings::load(Args::parse()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    };
+    logger::init(settings.verbosity, settings.syslog);
+    info!("Starting dsp-to-serial {}", env!("CARGO_PKG_VERSION"));
+
+    if settings.simulate {
+        let (memory, mut msgbox) = simulated::spawn_echo_dsp().unwrap();
+        let mut handler = CommunicationHandler::new(memory, Side::Arm);
+        handler.wait_dsp_set_init();
+        info!("Started simulated DSP!");
+
+        let e = bridge(&mut msgbox, &mut handler, &settings.link_path);
+        error!("Failed to wait for the DSP and serial port: {}", e);
+        exit(1);
+    }
+
+    let mmap = sharespace_mmap(&settings.dsp_debug_device);
+    debug!("Got sharespace mmap!");
+    let kbuf = kbuf_use_new_buf(
+        &settings.kbuf_mgr_device,
+        &settings.kbuf_name,
+        settings.kbuf_size,
+        mmap.dsp_sharespace.arm_write_addr,
+    )
+    .unwrap();
+    debug!("Got kbuf mmap!");
+    let mut handler = CommunicationHandler::new(DspMemory::new(mmap, kbuf), Side::Arm);
+    debug!("Got communication handler!");
+    handler.memory.init_no_mmap();
+    debug!("Done init_no_mmap!");
     handler.wait_dsp_set_init();
-    println!("Done DSP init!");
-    let mut msgbox = MsgboxEndpoint::new().unwrap();
-    println!("Got msgbox endpoint!");
+    info!("Done DSP init!");
+    let mut msgbox = MsgboxEndpoint::new(
+        &settings.rpmsg_ctrl_device,
+        &settings.endpoint_name,
+        settings.endpoint_src,
+        settings.endpoint_dst,
+    )
+    .unwrap();
+    debug!("Got msgbox endpoint!");
+
+    let e = bridge(&mut msgbox, &mut handler, &settings.link_path);
+    error!("Failed to wait for the DSP and serial port: {}", e);
+    exit(1);
+}
 
+// Connects the DSP to a new pty linked at `link_path` until the process is killed. Only returns if
+// waiting for events fails.
+fn bridge(msgbox : &mut impl Signal, handler : &mut CommunicationHandler<impl SharedMemory>, link_path: &Path) -> Errno {
     let (mut master, slave) = TTYPort::pair().expect("Unable to create ptty pair");
     master.set_timeout(Duration::ZERO).unwrap();
 
-    let mut link_path = std::env::temp_dir();
-    link_path.push("dsp-serial");
-
-    let _ = remove_file(&link_path);
+    let _ = remove_file(link_path);
     let name = slave.name().unwrap();
-    symlink(name, &link_path).unwrap();
+    symlink(name, link_path).unwrap();
+
+    info!("Created serial port at {:?}", link_path);
 
-    println!("Created serial port at {:?}", link_path);
+    let mut pending = vec![];
+    let mut unwritten = vec![];
+    let mut port_retry = Retry::default();
+    let mut dsp_retry = Retry::default();
 
     loop {
-        read_dsp(&mut msgbox, &mut handler, &mut master);
-        write_dsp(&mut msgbox, &mut handler, &mut master);
-        std::thread::sleep(Duration::from_millis(2));
+        if let Err(e) = wait_for_events(msgbox, &master, &pending, &unwritten, &port_retry, &dsp_retry) {
+            return e;
+        }
+
+        read_dsp(msgbox, handler, &mut master, &mut unwritten);
+        write_dsp(msgbox, handler, &mut master, &mut pending, &mut port_retry, &mut dsp_retry);
     }
 }
@@ -1,17 +1,18 @@
 use std::{
     fs::{self, File},
     io::Read,
-    os::fd::{AsFd, AsRawFd, OwnedFd},
-    path::PathBuf, sync::{Arc, Mutex},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    path::{Path, PathBuf},
 };
 
 use nix::{
     errno::Errno, fcntl::{open, OFlag}, ioctl_write_ptr, poll::{self, PollFd, PollTimeout}, sys::stat::Mode, unistd::{read, write}
 };
 
-use crate::{error::ApplicationError, util::u8_slice_to_string};
+use log::{debug, trace, warn};
+
+use crate::{error::ApplicationError, util::{string_to_u8_array, u8_slice_to_string}};
 
-const RPMSG_CTRL_DEV: &str = "/dev/rpmsg_ctrl0";
 ioctl_write_ptr!(rpmsg_create_ept_ioctl, 0xb5, 0x1, RpmsgEndpointInfo);
 
 #[repr(C)]
@@ -21,24 +22,33 @@ struct RpmsgEndpointInfo {
     dst: u32,
 }
 
-impl Default for RpmsgEndpointInfo {
-    fn default() -> Self {
-        let mut buf = [0u8; 32];
-        buf[..11].copy_from_slice(b"msgbox_demo");
-
+impl RpmsgEndpointInfo {
+    fn new(name: &str, src: u32, dst: u32) -> Self {
         RpmsgEndpointInfo {
-            name: buf,
-            src: 0x3,
-            dst: 0xffffffff,
+            name: string_to_u8_array(name),
+            src,
+            dst,
         }
     }
 }
 
 pub struct MsgboxEndpoint {
+    #[allow(dead_code)] // Kept open for as long as the endpoint is used
     msgbox_fd_ctrl: OwnedFd,
     msgbox_fd_ept: OwnedFd,
-    msgbox_new_msg_read: u16,
-    msgbox_new_msg_write: u16,
+}
+
+/// Doorbell between ARM and DSP. Every signal carries a 32 bit word.
+///
+/// The file descriptor is readable while a signal is waiting, so it can be polled together with
+/// other descriptors.
+pub trait Signal: AsFd {
+    /// Whether a signal is waiting to be read
+    fn has_signal(&mut self) -> bool;
+
+    fn read_signal(&mut self) -> Result<u32, ApplicationError>;
+
+    fn send_signal(&mut self, value: u32) -> Result<(), ApplicationError>;
 }
 
 fn wrap_ioctl_negative_invalid(result: Result<i32, Errno>) -> Result<i32, Errno> {
@@ -90,10 +100,16 @@ fn get_ept_interface_by_name(rpmsg_endpoint_info: &RpmsgEndpointInfo) -> Option<
 }
 
 impl MsgboxEndpoint {
-    pub fn new() -> Result<MsgboxEndpoint, ApplicationError> {
-        let msgbox_fd_ctrl = open(RPMSG_CTRL_DEV, OFlag::O_RDWR, Mode::empty())?;
+    /// Creates the endpoint `name` through the rpmsg control device `ctrl_device` and opens it.
+    pub fn new(
+        ctrl_device: &Path,
+        name: &str,
+        src: u32,
+        dst: u32,
+    ) -> Result<MsgboxEndpoint, ApplicationError> {
+        let msgbox_fd_ctrl = open(ctrl_device, OFlag::O_RDWR, Mode::empty())?;
 
-        let ept_info = RpmsgEndpointInfo::default();
+        let ept_info = RpmsgEndpointInfo::new(name, src, dst);
 
         wrap_ioctl_negative_invalid(unsafe {
             rpmsg_create_ept_ioctl(msgbox_fd_ctrl.as_raw_fd(), &ept_info)
@@ -109,76 +125,45 @@ impl MsgboxEndpoint {
 
         let msgbox_fd_ept = open(&ept_interface, OFlag::O_RDWR, Mode::empty())?;
 
-        println!("Opened msgbox!");
+        debug!("Opened msgbox!");
 
         Ok(MsgboxEndpoint {
-            msgbox_fd_ctrl: msgbox_fd_ctrl,
-            msgbox_fd_ept: msgbox_fd_ept,
-            msgbox_new_msg_read: 0,
-            msgbox_new_msg_write: 0,
+            msgbox_fd_ctrl,
+            msgbox_fd_ept,
         })
     }
+}
+
+impl AsFd for MsgboxEndpoint {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.msgbox_fd_ept.as_fd()
+    }
+}
 
-    pub fn msgbox_has_signal(&mut self) -> bool
-    {
+impl Signal for MsgboxEndpoint {
+    fn has_signal(&mut self) -> bool {
         let poll_fd = PollFd::new(self.msgbox_fd_ept.as_fd(), poll::PollFlags::POLLIN);
-        return match poll::poll(&mut [poll_fd], PollTimeout::ZERO) {
+        match poll::poll(&mut [poll_fd], PollTimeout::ZERO) {
             Ok(num) => num > 0,
             Err(_) => false,
-        };
+        }
     }
 
-    pub fn msgbox_read_signal(
-        &mut self,
-        sharespace_arm_addr_read: u16,
-    ) -> Result<bool, ApplicationError> {
+    fn read_signal(&mut self) -> Result<u32, ApplicationError> {
         let mut buf = [0u8; 4];
         let ret = read(&self.msgbox_fd_ept, &mut buf)?;
 
         if ret != 4 {
-            println!("Warn: Read msgbox size is not 4, but {}", ret);
+            warn!("Read msgbox size is not 4, but {}", ret);
         }
 
-        let data_recv = u32::from_le_bytes(buf[..].try_into().unwrap());
-
-        self.msgbox_new_msg_read = data_recv as u16;
-        self.msgbox_new_msg_write = (data_recv >> 16) as u16;
-
-        println!(
-            "Msgbox read signal: read {}, write {}",
-            self.msgbox_new_msg_read, self.msgbox_new_msg_write
-        );
-
-        if self.msgbox_new_msg_write >= 5000 {
-            return Ok(false);
-        }
-
-        if self.msgbox_new_msg_write == sharespace_arm_addr_read {
-            return Ok(false);
-        }
-
-        Ok(true)
+        Ok(u32::from_le_bytes(buf))
     }
 
-    pub fn msgbox_send_signal(
-        &mut self,
-        sharespace_arm_addr_read: u16,
-        sharespace_arm_addr_write: u16,
-    ) -> Result<(), ApplicationError> {
-        let data_send =
-            ((sharespace_arm_addr_write as u32) << 16) | sharespace_arm_addr_read as u32;
-        let a = write(&self.msgbox_fd_ept, &data_send.to_le_bytes()[..])?;
-        println!("Wrote {} bytes ({:#x}) to msgbox", a, data_send);
+    fn send_signal(&mut self, value: u32) -> Result<(), ApplicationError> {
+        let a = write(&self.msgbox_fd_ept, &value.to_le_bytes()[..])?;
+        trace!("Wrote {} bytes ({:#x}) to msgbox", a, value);
 
         Ok(())
     }
-
-    pub fn try_clone(&self) -> Result<MsgboxEndpoint, ApplicationError> {
-        Ok(MsgboxEndpoint {
-            msgbox_fd_ctrl: self.msgbox_fd_ctrl.try_clone()?,
-            msgbox_fd_ept: self.msgbox_fd_ept.try_clone()?,
-            msgbox_new_msg_read: self.msgbox_new_msg_read,
-            msgbox_new_msg_write: self.msgbox_new_msg_write,
-        })
-    }
-}
\ No newline at end of file
+}
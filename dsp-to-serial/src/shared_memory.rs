@@ -0,0 +1,88 @@
+use std::ffi::c_void;
+
+use nix::libc::{msync, MS_INVALIDATE};
+
+use crate::{
+    communication_handler::{BUFFER_LEN, MsgHead, Side},
+    kbuf::UserWrapperBufData,
+    sharespace::Sharespace,
+};
+
+/// Memory shared between ARM and DSP: one buffer of `BUFFER_LEN` bytes written by each side.
+pub trait SharedMemory {
+    fn read(&self, buffer: Side, offset: usize, out: &mut [u8]);
+
+    fn write(&mut self, buffer: Side, offset: usize, data: &[u8]);
+
+    /// Drops cached contents of `buffer`, so the latest writes of the other side are seen
+    fn invalidate(&mut self, _buffer: Side) {}
+}
+
+/// The real thing: the kbuf the DSP was pointed at through `/dev/dsp_debug`.
+pub struct DspMemory {
+    sharespace: Sharespace,
+    user_buf: UserWrapperBufData,
+}
+
+impl DspMemory {
+    pub fn new(sharespace: Sharespace, user_buf: UserWrapperBufData) -> Self {
+        DspMemory {
+            sharespace,
+            user_buf,
+        }
+    }
+
+    fn offset(buffer: Side) -> usize {
+        match buffer {
+            // pVirArmBuf
+            Side::Arm => 0,
+            // pVirDspBuf
+            Side::Dsp => BUFFER_LEN,
+        }
+    }
+
+    pub fn init_no_mmap(&mut self) {
+        let head_offset = BUFFER_LEN - size_of::<MsgHead>();
+        let mut head = MsgHead::from_bytes(
+            self.sharespace.write_buffer.as_ref()[head_offset..]
+                .try_into()
+                .unwrap(),
+        );
+
+        head.init_state = if head.init_state == 1 || head.init_state == 2 {
+            2
+        } else {
+            1
+        };
+        head.read_addr = self.user_buf.buf.pa + BUFFER_LEN as u32;
+        head.write_addr = self.user_buf.buf.pa;
+
+        self.sharespace.write_buffer.as_mut()[head_offset..].copy_from_slice(&head.to_bytes())
+    }
+}
+
+impl SharedMemory for DspMemory {
+    fn read(&self, buffer: Side, offset: usize, out: &mut [u8]) {
+        let start = Self::offset(buffer) + offset;
+        out.copy_from_slice(&self.user_buf.addr.as_ref()[start..start + out.len()]);
+    }
+
+    fn write(&mut self, buffer: Side, offset: usize, data: &[u8]) {
+        let start = Self::offset(buffer) + offset;
+        self.user_buf.addr.as_mut()[start..start + data.len()].copy_from_slice(data);
+    }
+
+    fn invalidate(&mut self, buffer: Side) {
+        unsafe {
+            msync(
+                self.user_buf
+                    .addr
+                    .as_mut_ptr()
+                    .add(Self::offset(buffer))
+                    .cast::<c_void>(),
+                BUFFER_LEN,
+                MS_INVALIDATE,
+            );
+        }
+    }
+}
@@ -1,13 +1,15 @@
-use std::os::fd::{AsRawFd, OwnedFd};
+use std::{os::fd::{AsRawFd, OwnedFd}, path::Path};
 
 use memmap2::{MmapMut, MmapOptions};
 use nix::{errno::Errno, fcntl::{open, OFlag}, ioctl_readwrite_bad, sys::stat::Mode};
 
+use log::debug;
+
 use crate::util::wrap_ioctl_negative_invalid;
 
 #[repr(C)]
 #[derive(Default, Debug)]
-struct DebugMessage {
+pub struct DebugMessage {
     pub sys_cnt: u32,
     pub log_head_addr: u32,
     pub log_end_addr: u32,
@@ -33,6 +35,7 @@ pub struct DspSharespace {
     pub debug_msg: DebugMessage,
 }
 
+#[allow(dead_code)] // The DSP write space is only mapped through kbuf
 enum ChooseShareSpace {
     ChooseDspWriteSpace = 0,
     ChooseArmWriteSpace = 1,
@@ -49,7 +52,7 @@ fn choose_sharespace(
     let raw_fd = fd.as_raw_fd();
     wrap_ioctl_negative_invalid(unsafe { read_debug_message(raw_fd, msg) })?;
 
-    println!("Before choose: {:#?}", msg);
+    debug!("Before choose: {:#?}", msg);
 
     msg.mmap_phy_addr = match choose {
         ChooseShareSpace::ChooseDspWriteSpace => msg.dsp_write_addr,
@@ -61,23 +64,24 @@ fn choose_sharespace(
     Ok(())
 }
 
-fn sharespace_open() -> Result<OwnedFd, Errno> {
+fn sharespace_open(device: &Path) -> Result<OwnedFd, Errno> {
     open(
-        "/dev/dsp_debug",
+        device,
         OFlag::O_RDWR | OFlag::O_SYNC | OFlag::O_NONBLOCK,
         Mode::empty(),
     )
 }
 
 pub struct Sharespace {
+    #[allow(dead_code)] // Kept open for as long as the buffer is mapped
     fd: OwnedFd,
     pub dsp_sharespace: DspSharespace,
     pub write_buffer: MmapMut, // ARM buffer - pu8ArmBuf
 }
 
-pub fn sharespace_mmap() -> Sharespace {
+pub fn sharespace_mmap(device: &Path) -> Sharespace {
     let mut dsp_sharespace = DspSharespace::default();
-    let fd = sharespace_open().unwrap();
+    let fd = sharespace_open(device).unwrap();
 
     choose_sharespace(
         &fd,
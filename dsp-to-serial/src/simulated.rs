@@ -0,0 +1,132 @@
+use std::{
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::net::UnixDatagram,
+    },
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use nix::poll::{self, PollFd, PollTimeout};
+
+use crate::{
+    communication_handler::{BUFFER_LEN, CommunicationHandler, Side},
+    error::ApplicationError,
+    msgbox::Signal,
+    shared_memory::SharedMemory,
+};
+
+/// Both buffers of the shared memory, in process. Clones share the same memory.
+#[derive(Clone)]
+pub struct SimulatedMemory {
+    buffers: Arc<Mutex<[[u8; BUFFER_LEN]; 2]>>,
+}
+
+impl SimulatedMemory {
+    pub fn new() -> Self {
+        SimulatedMemory {
+            buffers: Arc::new(Mutex::new([[0u8; BUFFER_LEN]; 2])),
+        }
+    }
+}
+
+impl SharedMemory for SimulatedMemory {
+    fn read(&self, buffer: Side, offset: usize, out: &mut [u8]) {
+        let buffers = self.buffers.lock().unwrap();
+        out.copy_from_slice(&buffers[buffer as usize][offset..offset + out.len()]);
+    }
+
+    fn write(&mut self, buffer: Side, offset: usize, data: &[u8]) {
+        let mut buffers = self.buffers.lock().unwrap();
+        buffers[buffer as usize][offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+/// One end of an in process msgbox. Every signal is a datagram with the little endian word.
+pub struct SimulatedSignal {
+    socket: UnixDatagram,
+}
+
+impl SimulatedSignal {
+    pub fn pair() -> Result<(SimulatedSignal, SimulatedSignal), ApplicationError> {
+        let (a, b) = UnixDatagram::pair()?;
+
+        Ok((SimulatedSignal { socket: a }, SimulatedSignal { socket: b }))
+    }
+}
+
+impl AsFd for SimulatedSignal {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl Signal for SimulatedSignal {
+    fn has_signal(&mut self) -> bool {
+        let poll_fd = PollFd::new(self.socket.as_fd(), poll::PollFlags::POLLIN);
+        match poll::poll(&mut [poll_fd], PollTimeout::ZERO) {
+            Ok(num) => num > 0,
+            Err(_) => false,
+        }
+    }
+
+    fn read_signal(&mut self) -> Result<u32, ApplicationError> {
+        let mut buf = [0u8; 4];
+        self.socket.recv(&mut buf)?;
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn send_signal(&mut self, value: u32) -> Result<(), ApplicationError> {
+        self.socket.send(&value.to_le_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Starts a DSP in a thread that sends back everything it receives.
+///
+/// Returns the memory and msgbox the ARM side uses to talk to it.
+pub fn spawn_echo_dsp() -> Result<(SimulatedMemory, SimulatedSignal), ApplicationError> {
+    let memory = SimulatedMemory::new();
+    let (arm_signal, mut dsp_signal) = SimulatedSignal::pair()?;
+    let mut dsp = CommunicationHandler::new(memory.clone(), Side::Dsp);
+
+    thread::spawn(move || {
+        let mut pending = vec![];
+
+        loop {
+            // Retries writing to a full ring every millisecond, otherwise waits for the ARM side
+            let timeout = if pending.is_empty() {
+                PollTimeout::NONE
+            } else {
+                PollTimeout::from(1u8)
+            };
+            let poll_fd = PollFd::new(dsp_signal.as_fd(), poll::PollFlags::POLLIN);
+            let _ = poll::poll(&mut [poll_fd], timeout);
+
+            if dsp_signal.has_signal() {
+                match dsp.read_signal(&mut dsp_signal) {
+                    Ok(true) => match dsp.dsp_mem_read() {
+                        Ok(data) => pending.extend(data),
+                        Err(_) => return,
+                    },
+                    Ok(false) => {}
+                    // The ARM side is gone
+                    Err(_) => return,
+                }
+            }
+
+            if !pending.is_empty() {
+                match dsp.dsp_mem_write(&mut dsp_signal, &pending) {
+                    Ok(len) => {
+                        pending.drain(..len);
+                    }
+                    Err(_) => return,
+                }
+            }
+        }
+    });
+
+    Ok((memory, arm_signal))
+}
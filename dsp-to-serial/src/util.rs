@@ -10,6 +10,14 @@ pub(crate) fn wrap_ioctl_negative_invalid(result: Result<i32, Errno>) -> Result<
     }
 }
 
+// Names the kernel expects in a 32 byte field ending with a NUL
+pub(crate) fn string_to_u8_array(name: &str) -> [u8; 32] {
+    let mut buf = [0u8; 32];
+    let len = name.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    buf
+}
+
 pub(crate) fn u8_slice_to_string(slice: &[u8]) -> String {
     let len = slice.iter().position(|&b| b == 0).unwrap_or(slice.len());
     String::from_utf8_lossy(&slice[..len]).to_string()
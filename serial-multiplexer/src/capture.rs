@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::compress::decompress;
+use crate::frame::{FLAG_ACK, FLAG_COMPRESSED, FLAG_SYNC, Frame, MAX_VARINT_PAYLOAD_LEN};
+
+// Captures are pcapng files with a single interface of link type USER0.
+// Every packet starts with a pseudo header, followed by the frame payload:
+// [direction][id][flags][seq]
+// direction is 0 for frames sent to the peer and 1 for frames received from it.
+// Sequence numbers and flags other than FLAG_COMPRESSED are only meaningful in reliable mode.
+// Compressed frames are captured the way they were sent.
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+pub const LINKTYPE_USER0: u16 = 147;
+const PSEUDO_HEADER_LEN: usize = 4;
+// Largest block we write: block header, packet block fields, pseudo header, padded payload and trailer
+const MAX_BLOCK_LEN: usize =
+    (8 + 20 + PSEUDO_HEADER_LEN + MAX_VARINT_PAYLOAD_LEN).next_multiple_of(4) + 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent to the peer over the multiplexed link
+    Sent,
+    /// Received from the peer over the multiplexed link
+    Received,
+}
+
+#[derive(Clone, Debug)]
+pub struct CapturedFrame {
+    /// Time since the Unix epoch, with microsecond resolution
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+
+/// Records every frame on the multiplexed link to a pcapng file.
+pub struct Capture {
+    writer: Mutex<BufWriter<File>>,
+}
+
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_len = (12 + body.len() + padding) as u32;
+
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&[0u8; 3][..padding])?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+impl Capture {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        let mut section = vec![];
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes());
+        section.extend_from_slice(&0u16.to_le_bytes());
+        // Section length is unknown
+        section.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut writer, BLOCK_SECTION_HEADER, &section)?;
+
+        let mut interface = vec![];
+        interface.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        // No snap length limit. Timestamps use the default resolution of microseconds.
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut writer, BLOCK_INTERFACE_DESCRIPTION, &interface)?;
+
+        writer.flush()?;
+
+        Ok(Capture {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Appends a frame. Failures are logged, the link keeps running without a complete capture.
+    pub fn record(&self, direction: Direction, frame: &Frame) {
+        if let Err(e) = self.write_frame(direction, frame) {
+            eprintln!("Failed to write capture: {}", e);
+        }
+    }
+
+    fn write_frame(&self, direction: Direction, frame: &Frame) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let direction = match direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        };
+
+        let packet_len = (PSEUDO_HEADER_LEN + frame.payload.len()) as u32;
+
+        let mut packet = Vec::with_capacity(20 + packet_len as usize);
+        // Interface id
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        packet.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        packet.extend_from_slice(&packet_len.to_le_bytes());
+        packet.extend_from_slice(&packet_len.to_le_bytes());
+        packet.extend_from_slice(&[direction, frame.id, frame.flags, frame.seq]);
+        packet.extend_from_slice(&frame.payload);
+
+        let mut writer = self.writer.lock().expect("Failed to lock capture");
+        write_block(&mut *writer, BLOCK_ENHANCED_PACKET, &packet)?;
+        // Keep the file usable if we get killed
+        writer.flush()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> io::Result<u32> {
+    let bytes = bytes
+        .get(offset..offset + 4)
+        .ok_or_else(|| invalid("Block is truncated"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads the frames of a capture written by [`Capture`]. Unknown blocks are skipped.
+pub fn read_capture(mut reader: impl Read) -> io::Result<Vec<CapturedFrame>> {
+    let mut frames = vec![];
+    let mut linktype = None;
+    let mut header = [0u8; 8];
+
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(frames),
+            Err(e) => return Err(e),
+        }
+
+        let block_type = u32_at(&header, 0)?;
+        let total_len = u32_at(&header, 4)? as usize;
+
+        if !(12..=MAX_BLOCK_LEN).contains(&total_len) || !total_len.is_multiple_of(4) {
+            return Err(invalid("Invalid block length"));
+        }
+
+        let mut body = vec![0u8; total_len - 8];
+        reader.read_exact(&mut body)?;
+        let body = &body[..body.len() - 4];
+
+        match block_type {
+            BLOCK_SECTION_HEADER if u32_at(body, 0)? != BYTE_ORDER_MAGIC => {
+                return Err(invalid("Only little-endian captures are supported"));
+            }
+            BLOCK_INTERFACE_DESCRIPTION => {
+                let bytes = body.get(..2).ok_or_else(|| invalid("Block is truncated"))?;
+                linktype = Some(u16::from_le_bytes([bytes[0], bytes[1]]));
+            }
+            BLOCK_ENHANCED_PACKET => {
+                if linktype != Some(LINKTYPE_USER0) {
+                    return Err(invalid("Not a serial-multiplexer capture"));
+                }
+
+                let timestamp = (u32_at(body, 4)? as u64) << 32 | u32_at(body, 8)? as u64;
+                let captured_len = u32_at(body, 12)? as usize;
+                let packet = body
+                    .get(20..20 + captured_len)
+                    .filter(|packet| packet.len() >= PSEUDO_HEADER_LEN)
+                    .ok_or_else(|| invalid("Packet is truncated"))?;
+
+                let direction = match packet[0] {
+                    0 => Direction::Sent,
+                    1 => Direction::Received,
+                    _ => return Err(invalid("Unknown direction")),
+                };
+
+                frames.push(CapturedFrame {
+                    timestamp: Duration::from_micros(timestamp),
+                    direction,
+                    frame: Frame {
+                        id: packet[1],
+                        flags: packet[2],
+                        seq: packet[3],
+                        payload: packet[PSEUDO_HEADER_LEN..].to_vec(),
+                    },
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Data of channel `id` in the order the receiving side delivered it, with the time it was captured.
+///
+/// ACK and SYNC frames are skipped. Once a channel was synced in reliable mode, retransmitted and out of
+/// order frames are dropped just like the receiver does. Compressed frames are decompressed.
+pub fn channel_data(
+    frames: &[CapturedFrame],
+    id: u8,
+    direction: Direction,
+) -> Vec<(Duration, Vec<u8>)> {
+    let mut expected: Option<u8> = None;
+    let mut data = vec![];
+
+    for captured in frames {
+        let frame = &captured.frame;
+
+        if frame.id != id || captured.direction != direction || frame.flags & FLAG_ACK != 0 {
+            continue;
+        }
+
+        if frame.flags & FLAG_SYNC != 0 {
+            expected = Some(frame.seq.wrapping_add(1));
+            continue;
+        }
+
+        if let Some(next) = &mut expected {
+            if frame.seq != *next {
+                continue;
+            }
+
+            *next = next.wrapping_add(1);
+        }
+
+        if frame.flags & FLAG_COMPRESSED == 0 {
+            data.push((captured.timestamp, frame.payload.clone()));
+            continue;
+        }
+
+        match decompress(&frame.payload, MAX_VARINT_PAYLOAD_LEN) {
+            Ok(payload) => data.push((captured.timestamp, payload)),
+            Err(e) => eprintln!("{} in captured frame, skipping it", e),
+        }
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_block_is_rejected() {
+        let mut block = vec![];
+        block.extend_from_slice(&BLOCK_ENHANCED_PACKET.to_le_bytes());
+        block.extend_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+
+        let error = read_capture(&block[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn largest_frame_fits() {
+        let path = std::env::temp_dir().join(format!("capture-test-{}.pcapng", std::process::id()));
+        let capture = Capture::create(&path).unwrap();
+        let frame = Frame {
+            id: 1,
+            flags: 0,
+            seq: 0,
+            payload: vec![0x55; MAX_VARINT_PAYLOAD_LEN],
+        };
+        capture.record(Direction::Sent, &frame);
+        drop(capture);
+
+        let frames = read_capture(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].frame.payload, frame.payload);
+    }
+}
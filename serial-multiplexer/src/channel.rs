@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::capture::Direction;
+use crate::compress::compress;
+use crate::frame::MAX_VARINT_PAYLOAD_LEN;
+use crate::scheduler::{DropPolicy, MainBus, QueueSettings};
+use crate::stats::{ChannelStats, Stats};
+use crate::tee::Tee;
+use crate::transport::Transport;
+
+// Ids from here up are reserved for control messages between the two ends of the link
+pub const FIRST_RESERVED_ID: u8 = 0xF0;
+
+// Data from the peer a channel holds while its transport doesn't take it, e.g. a pty nobody has
+// open. Beyond that frames are dropped, or left unacknowledged in reliable mode.
+const MAX_OUTPUT_LEN: usize = 64 * 1024;
+
+pub struct DataBlock {
+    pub id: u8,
+    pub data: Vec<u8>,
+    /// `data` is an LZ4 block, see [`compress`](crate::compress::compress)
+    pub compressed: bool,
+}
+
+// Acknowledgement state of a channel in reliable mode.
+// `seq` is the next sequence number the receiving side expects.
+pub struct Ack {
+    pub id: u8,
+    pub seq: u8,
+    pub need_sync: bool,
+}
+
+/// Lets a channel hold back what it reads from its transport and send it in fewer, larger frames.
+#[derive(Clone, Copy, Debug)]
+pub struct CoalesceSettings {
+    /// Largest frame payload, or `None` for as much as fits in a frame. A full buffer is sent
+    /// right away.
+    pub max_bytes: Option<usize>,
+    /// How long the first buffered byte may wait for more. Zero sends every read right away.
+    pub max_latency: Duration,
+}
+
+impl Default for CoalesceSettings {
+    fn default() -> Self {
+        CoalesceSettings {
+            max_bytes: None,
+            max_latency: Duration::ZERO,
+        }
+    }
+}
+
+/// A local endpoint whose traffic is carried over the multiplexed link under `id`.
+pub struct Channel {
+    pub id: u8,
+    /// Shown in stats and announced to the peer if the channel table is announced
+    pub name: Option<String>,
+    pub transport: Box<dyn Transport>,
+    pub coalesce: CoalesceSettings,
+    pub queue: QueueSettings,
+    /// Compress data sent on this channel, if the peer compresses it as well
+    pub compress: bool,
+    /// Copies the channel's traffic to a log or mirror
+    pub tee: Option<Tee>,
+}
+
+impl Channel {
+    pub fn new(id: u8, transport: impl Transport + 'static) -> Self {
+        Channel {
+            id,
+            name: None,
+            transport: Box::new(transport),
+            coalesce: CoalesceSettings::default(),
+            queue: QueueSettings::default(),
+            compress: false,
+            tee: None,
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn with_coalesce(mut self, settings: CoalesceSettings) -> Self {
+        self.coalesce = settings;
+        self
+    }
+
+    pub fn with_queue(mut self, settings: QueueSettings) -> Self {
+        self.queue = settings;
+        self
+    }
+
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn with_tee(mut self, tee: Tee) -> Self {
+        self.tee = Some(tee);
+        self
+    }
+}
+
+// A channel while the multiplexer runs
+pub(crate) struct ChannelState {
+    pub channel: Channel,
+    pub stats: Arc<ChannelStats>,
+    // Data from the peer, waiting until the transport takes it
+    pub output: VecDeque<u8>,
+    // Data read from the transport, held back to be sent in fewer, larger frames
+    coalesced: Vec<u8>,
+    coalesce_deadline: Option<Instant>,
+    // Most data held back for a frame
+    coalesce_bytes: usize,
+    // Whether the channel was last reported open to the peer
+    pub open: bool,
+    // The transport failed and is reopened then
+    pub retry_at: Option<Instant>,
+    // The transport failed for good
+    pub closed: bool,
+    // Both ends asked for compression on this channel
+    pub compressing: bool,
+}
+
+impl ChannelState {
+    /// `max_payload_len` is the most data that fits in a frame
+    pub fn new(channel: Channel, max_payload_len: usize, stats: &Stats) -> Self {
+        let coalesce_bytes = channel.coalesce.max_bytes.unwrap_or(max_payload_len);
+
+        assert!(
+            (1..=max_payload_len).contains(&coalesce_bytes),
+            "Channel {} coalesces {} bytes, a frame takes 1 to {}",
+            channel.id,
+            coalesce_bytes,
+            max_payload_len
+        );
+
+        if let Some(name) = &channel.name {
+            stats.set_name(channel.id, name);
+        }
+
+        ChannelState {
+            stats: stats.channel(channel.id),
+            channel,
+            output: VecDeque::new(),
+            coalesced: vec![],
+            coalesce_deadline: None,
+            coalesce_bytes,
+            open: false,
+            retry_at: None,
+            closed: false,
+            compressing: false,
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.channel.id
+    }
+
+    /// Descriptor to wait on, unless the transport is down
+    pub fn fd(&self) -> Option<RawFd> {
+        if self.closed || self.retry_at.is_some() {
+            return None;
+        }
+
+        self.channel.transport.fd()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.fd().is_some() && self.channel.transport.is_connected()
+    }
+
+    pub fn wants_read(&self, bus: &MainBus) -> bool {
+        self.coalesced.len() < self.coalesce_bytes
+            && (self.channel.queue.policy != DropPolicy::Block || bus.has_room(self.id()))
+    }
+
+    /// The transport has to take some data before more from the peer fits
+    pub fn output_full(&self) -> bool {
+        self.output.len() >= MAX_OUTPUT_LEN
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.output.is_empty() && self.channel.transport.is_connected()
+    }
+
+    /// When held back data has to be sent, if it can be
+    pub fn deadline(&self, bus: &MainBus) -> Option<Instant> {
+        self.coalesce_deadline.filter(|_| self.can_flush(bus))
+    }
+
+    fn can_flush(&self, bus: &MainBus) -> bool {
+        self.channel.queue.policy != DropPolicy::Block || bus.has_room(self.id())
+    }
+
+    /// Reads once from the transport and queues what was read on the main bus.
+    pub fn read(&mut self, bus: &mut MainBus, now: Instant) -> io::Result<()> {
+        let settings = self.channel.coalesce;
+        let mut buffer = [0u8; MAX_VARINT_PAYLOAD_LEN];
+        let room = self.coalesce_bytes - self.coalesced.len();
+
+        let bytes = match self.channel.transport.read(&mut buffer[..room]) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if let Some(tee) = &mut self.channel.tee {
+            tee.record(self.channel.id, Direction::Sent, &buffer[..bytes]);
+        }
+
+        if settings.max_latency.is_zero() {
+            self.send(bus, buffer[..bytes].to_vec());
+            return Ok(());
+        }
+
+        self.coalesced.extend_from_slice(&buffer[..bytes]);
+        self.coalesce_deadline
+            .get_or_insert(now + settings.max_latency);
+
+        // A full buffer is sent right away
+        if self.coalesced.len() >= self.coalesce_bytes {
+            self.flush(bus);
+        }
+
+        Ok(())
+    }
+
+    /// Sends held back data whose time is up.
+    pub fn flush_due(&mut self, bus: &mut MainBus, now: Instant) {
+        if self
+            .coalesce_deadline
+            .is_some_and(|deadline| deadline <= now)
+            && self.can_flush(bus)
+        {
+            self.flush(bus);
+        }
+    }
+
+    fn flush(&mut self, bus: &mut MainBus) {
+        self.coalesce_deadline = None;
+
+        if self.coalesced.is_empty() {
+            return;
+        }
+
+        let data = std::mem::take(&mut self.coalesced);
+        self.send(bus, data);
+    }
+
+    fn send(&self, bus: &mut MainBus, data: Vec<u8>) {
+        let compressed = self.compressing.then(|| compress(&data)).flatten();
+
+        bus.send(DataBlock {
+            id: self.id(),
+            compressed: compressed.is_some(),
+            data: compressed.unwrap_or(data),
+        });
+    }
+
+    /// Writes as much of the peer's data as the transport takes.
+    pub fn write(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            let (data, _) = self.output.as_slices();
+
+            match self.channel.transport.write(data) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(bytes) => {
+                    if let Some(tee) = &mut self.channel.tee {
+                        tee.record(self.channel.id, Direction::Received, &data[..bytes]);
+                    }
+
+                    self.output.drain(..bytes);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,24 @@
+use lz4_flex::block::{self, DecompressError};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CompressError {
+    #[error("Corrupted compressed data: {0}")]
+    Corrupted(#[from] DecompressError),
+}
+
+/// Compresses a frame payload into an LZ4 block.
+///
+/// Returns `None` if that would not make it smaller, the payload is then sent as is.
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = block::compress(data);
+    (compressed.len() < data.len()).then_some(compressed)
+}
+
+/// Decompresses a payload from [`compress`]. Data that would expand beyond `max_len` is rejected.
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, CompressError> {
+    let mut buff = vec![0u8; max_len];
+    let len = block::decompress_into(data, &mut buff)?;
+    buff.truncate(len);
+    Ok(buff)
+}
@@ -1,32 +1,859 @@
-use clap::Parser;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand, ValueEnum};
 use serde::Deserialize;
+use toml::Spanned;
+
+use serial_multiplexer::channel::FIRST_RESERVED_ID;
+use serial_multiplexer::frame::{LengthEncoding, MAX_PAYLOAD_LEN};
+use serial_multiplexer::reliable::MAX_WINDOW_SIZE;
+use serial_multiplexer::secure::SEAL_OVERHEAD;
+use serial_multiplexer::serial_connection::DeviceSelector;
+use serial_multiplexer::{CoalesceSettings, DropPolicy, LogSettings, QueueSettings};
+
+use crate::pty::{PtySettings, parse_mode, resolve_group};
 
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+const DEFAULT_LOG_MAX_BYTES: u64 = 1024 * 1024;
+const DEFAULT_LOG_KEEP: usize = 5;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    /// A real serial port at `device_path`, or the USB device matching the `usb_*` fields
+    #[serde(alias = "real")]
+    Serial,
+    /// A virtual port, symlinked as `<link_dir>/<name>`
+    #[serde(alias = "virtual")]
+    Pty,
+    /// A TCP port listening on `listen` (e.g. "0.0.0.0:5000")
+    Tcp,
+    /// A Unix socket listening on the path in `listen`
+    Unix,
+}
+
+/// Which end of an asymmetric link this instance is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Role {
+    /// Has the devices. Announces its channel table to the peer.
+    Printer,
+    /// Creates a virtual port for every channel the peer announces. The config is optional.
+    Host,
+}
+
+/// What to do with data read from a channel while its queue is full.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhenFull {
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+/// One `[name]` table in the config file.
+///
+/// Optional fields and their defaults:
+/// - `baud_rate`: 115200. Only used by `serial` entries.
+/// - `usb_vid`, `usb_pid`, `usb_serial`: match any. Select a `serial` device instead of `device_path`.
+/// - `kind`: `serial` with --with-real-ports, `pty` with --with-virtual-ports.
+///   Entries without a kind are an error if neither flag is given.
+/// - `link_dir`: --link-dir, `<temp dir>/vtty` without it. Only used by `pty` entries.
+/// - `mode`: --pty-mode, the system default without it. Permissions of the pty, e.g. `0o660`.
+/// - `group`: --pty-group, the user's group without it. Group name or id owning the pty.
+/// - `coalesce_bytes`: as much as fits in a frame. That is 255 bytes, or 16383 with --large-frames,
+///   20 less with --psk-file. Larger values are an error.
+/// - `coalesce_ms`: 0, every read is sent right away.
+/// - `priority`: 0. Higher priorities are always sent first.
+/// - `weight`: 1. Share of the link among channels with the same priority.
+/// - `queue_size`: 64 blocks waiting to be sent.
+/// - `when_full`: `block`, stop reading from the channel until its queue has room.
+/// - `compress`: false. Compress data sent on this channel if the peer's entry has it as well.
+/// - `log_file`: none. Log the channel's traffic line by line with timestamps to this file.
+/// - `log_max_bytes`: 1 MiB. The log is rotated once it grows beyond this.
+/// - `log_keep`: 5 rotated logs.
+/// - `mirror`: none. Also show the channel's traffic on a read-only virtual port linked here.
+///   Relative paths are in the link dir. Uses `mode` and `group` like `pty` entries.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SerialEntryRaw {
-    pub device_path: String,
+    pub device_path: Option<String>,
+    pub usb_vid: Option<u16>,
+    pub usb_pid: Option<u16>,
+    pub usb_serial: Option<String>,
+    #[serde(default = "default_baud_rate")]
     pub baud_rate: u32,
     pub id: u8,
+    pub kind: Option<ChannelKind>,
+    pub listen: Option<String>,
+    pub link_dir: Option<PathBuf>,
+    pub mode: Option<u32>,
+    pub group: Option<String>,
+    pub coalesce_bytes: Option<usize>,
+    #[serde(default)]
+    pub coalesce_ms: u64,
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default = "default_when_full")]
+    pub when_full: WhenFull,
+    #[serde(default)]
+    pub compress: bool,
+    pub log_file: Option<PathBuf>,
+    pub log_max_bytes: Option<u64>,
+    pub log_keep: Option<usize>,
+    pub mirror: Option<PathBuf>,
+}
+
+fn default_baud_rate() -> u32 {
+    DEFAULT_BAUD_RATE
+}
+
+fn default_weight() -> u32 {
+    QueueSettings::default().weight
+}
+
+fn default_queue_size() -> usize {
+    QueueSettings::default().capacity
+}
+
+fn default_when_full() -> WhenFull {
+    WhenFull::Block
+}
+
+pub fn default_link_dir() -> PathBuf {
+    std::env::temp_dir().join("vtty")
+}
+
+/// A validated config entry
+pub struct ChannelConfig {
+    pub name: String,
+    pub id: u8,
+    pub endpoint: Endpoint,
+    pub coalesce: CoalesceSettings,
+    pub queue: QueueSettings,
+    pub compress: bool,
+    pub log: Option<LogSettings>,
+    pub mirror: Option<PtySettings>,
+}
+
+pub enum Endpoint {
+    Serial {
+        device: DeviceSelector,
+        baud_rate: u32,
+    },
+    Pty(PtySettings),
+    Tcp {
+        listen: String,
+    },
+    Unix {
+        path: PathBuf,
+    },
+}
+
+pub struct ConfigProblem {
+    pub line: Option<usize>,
+    pub entry: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+
+        if let Some(entry) = &self.entry {
+            write!(f, "[{}] ", entry)?;
+        }
+
+        write!(f, "{}", self.message)
+    }
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// Settings from the command line used for entries that leave them out
+pub struct ConfigDefaults {
+    pub kind: Option<ChannelKind>,
+    pub link_dir: PathBuf,
+    pub pty_mode: Option<u32>,
+    pub pty_group: Option<String>,
+    /// Whether the control channel is on, some fields do nothing without it
+    pub control: bool,
+    /// Most data a frame takes, see --large-frames and --psk-file
+    pub max_payload_len: usize,
+}
+
+/// Parses and validates the config file, reporting every problem found instead of just the first.
+pub fn load_config(
+    text: &str,
+    defaults: &ConfigDefaults,
+) -> Result<Vec<ChannelConfig>, Vec<ConfigProblem>> {
+    let raw: HashMap<Spanned<String>, Spanned<SerialEntryRaw>> = match toml::from_str(text) {
+        Ok(raw) => raw,
+        Err(e) => {
+            return Err(vec![ConfigProblem {
+                line: e.span().map(|span| line_of(text, span.start)),
+                entry: None,
+                message: e.message().to_string(),
+            }]);
+        }
+    };
+
+    if raw.is_empty() {
+        return Err(vec![ConfigProblem {
+            line: None,
+            entry: None,
+            message: "No serial ports found in the config file".to_string(),
+        }]);
+    }
+
+    let mut entries: Vec<_> = raw.into_iter().collect();
+    entries.sort_by_key(|(name, _)| name.span().start);
+
+    let mut problems = vec![];
+    let mut channels = vec![];
+    // Things two entries cannot share, mapped to the entry that claimed it first
+    let mut ids: HashMap<u8, (String, usize)> = HashMap::new();
+    let mut resources: HashMap<String, (String, usize)> = HashMap::new();
+
+    for (name, entry) in entries {
+        let line = line_of(text, name.span().start);
+        let name = name.into_inner();
+        let entry = entry.into_inner();
+
+        let mut problem = |message: String| {
+            problems.push(ConfigProblem {
+                line: Some(line),
+                entry: Some(name.clone()),
+                message,
+            });
+        };
+
+        if entry.id >= FIRST_RESERVED_ID {
+            problem(format!(
+                "id {} is reserved for control messages, use an id below {}",
+                entry.id, FIRST_RESERVED_ID
+            ));
+        }
+
+        if let Some((other, other_line)) = ids.get(&entry.id) {
+            problem(format!(
+                "id {} is already used by [{}] on line {}",
+                entry.id, other, other_line
+            ));
+        } else {
+            ids.insert(entry.id, (name.clone(), line));
+        }
+
+        if let Some(bytes) = entry.coalesce_bytes
+            && !(1..=defaults.max_payload_len).contains(&bytes)
+        {
+            let hint = if bytes > MAX_PAYLOAD_LEN && defaults.max_payload_len <= MAX_PAYLOAD_LEN {
+                ", more needs --large-frames"
+            } else {
+                ""
+            };
+
+            problem(format!(
+                "coalesce_bytes must be between 1 and {}{}",
+                defaults.max_payload_len, hint
+            ));
+        }
+
+        if entry.weight == 0 {
+            problem("weight must be at least 1".to_string());
+        }
+
+        if entry.queue_size == 0 {
+            problem("queue_size must be at least 1".to_string());
+        }
+
+        let kind = match entry.kind.or(defaults.kind) {
+            Some(kind) => kind,
+            None => {
+                problem(
+                    "no kind given. Set 'kind' or pass --with-virtual-ports or --with-real-ports"
+                        .to_string(),
+                );
+                continue;
+            }
+        };
+
+        let usb_given =
+            entry.usb_vid.is_some() || entry.usb_pid.is_some() || entry.usb_serial.is_some();
+        let uses_pty = kind == ChannelKind::Pty || entry.mirror.is_some();
+        let link_dir = entry.link_dir.as_ref().unwrap_or(&defaults.link_dir);
+
+        let mode = entry.mode.or(defaults.pty_mode);
+        if uses_pty && mode.is_some_and(|mode| mode > 0o777) {
+            problem("mode must be at most 0o777, write it in octal like 0o660".to_string());
+        }
+
+        let group = entry.group.as_ref().or(defaults.pty_group.as_ref());
+        let gid = group.filter(|_| uses_pty).and_then(|group| {
+            let gid = resolve_group(group);
+            if gid.is_none() {
+                problem(format!("group {} does not exist", group));
+            }
+            gid
+        });
+
+        let endpoint = match kind {
+            ChannelKind::Serial => {
+                let device = match (&entry.device_path, usb_given) {
+                    (Some(_), true) => {
+                        problem(
+                            "use either 'device_path' or the 'usb_*' fields, not both".to_string(),
+                        );
+                        continue;
+                    }
+                    (Some(device_path), false) => {
+                        // Missing devices are opened once they appear, e.g. a board that is
+                        // unplugged or in its bootloader. Globs are resolved at that point.
+                        if !device_path.contains(['*', '?']) && !Path::new(device_path).exists() {
+                            eprintln!(
+                                "Warning: line {}: [{}] device {} does not exist yet",
+                                line, name, device_path
+                            );
+                        }
+
+                        DeviceSelector::Path(device_path.clone())
+                    }
+                    (None, true) => DeviceSelector::Usb {
+                        vid: entry.usb_vid,
+                        pid: entry.usb_pid,
+                        serial: entry.usb_serial.clone(),
+                    },
+                    (None, false) => {
+                        problem("serial ports need a 'device_path' or 'usb_*' fields".to_string());
+                        continue;
+                    }
+                };
+
+                Endpoint::Serial {
+                    device,
+                    baud_rate: entry.baud_rate,
+                }
+            }
+            ChannelKind::Pty => {
+                if name.contains('/') || name == "." || name == ".." {
+                    problem(
+                        "virtual port names are used as file names and cannot contain '/'"
+                            .to_string(),
+                    );
+                }
+
+                Endpoint::Pty(PtySettings {
+                    link_path: link_dir.join(&name),
+                    mode,
+                    gid,
+                })
+            }
+            ChannelKind::Tcp => match &entry.listen {
+                Some(listen) => Endpoint::Tcp {
+                    listen: listen.clone(),
+                },
+                None => {
+                    problem("tcp ports need a 'listen' address".to_string());
+                    continue;
+                }
+            },
+            ChannelKind::Unix => match &entry.listen {
+                Some(listen) => Endpoint::Unix {
+                    path: PathBuf::from(listen),
+                },
+                None => {
+                    problem("unix ports need a 'listen' path".to_string());
+                    continue;
+                }
+            },
+        };
+
+        let ignored = [
+            (
+                "device_path",
+                entry.device_path.is_some() && kind != ChannelKind::Serial,
+            ),
+            ("usb_*", usb_given && kind != ChannelKind::Serial),
+            (
+                "listen",
+                entry.listen.is_some() && !matches!(kind, ChannelKind::Tcp | ChannelKind::Unix),
+            ),
+            ("link_dir", entry.link_dir.is_some() && !uses_pty),
+            ("mode", entry.mode.is_some() && !uses_pty),
+            ("group", entry.group.is_some() && !uses_pty),
+        ];
+
+        for (field, is_ignored) in ignored {
+            if is_ignored {
+                eprintln!(
+                    "Warning: line {}: [{}] '{}' is not used by {:?} ports",
+                    line, name, field, kind
+                );
+            }
+        }
+
+        // Both ends agree on compression over the control channel
+        if entry.compress && !defaults.control {
+            eprintln!(
+                "Warning: line {}: [{}] 'compress' is not used without --control",
+                line, name
+            );
+        }
+
+        if entry.log_file.is_none() {
+            for (field, is_set) in [
+                ("log_max_bytes", entry.log_max_bytes.is_some()),
+                ("log_keep", entry.log_keep.is_some()),
+            ] {
+                if is_set {
+                    eprintln!(
+                        "Warning: line {}: [{}] '{}' is not used without 'log_file'",
+                        line, name, field
+                    );
+                }
+            }
+        }
+
+        if entry.log_max_bytes == Some(0) {
+            problem("log_max_bytes must be at least 1".to_string());
+        }
+
+        let log = entry.log_file.as_ref().map(|path| LogSettings {
+            path: path.clone(),
+            max_bytes: entry.log_max_bytes.unwrap_or(DEFAULT_LOG_MAX_BYTES),
+            keep: entry.log_keep.unwrap_or(DEFAULT_LOG_KEEP),
+        });
+
+        let mirror = entry.mirror.as_ref().map(|path| PtySettings {
+            link_path: link_dir.join(path),
+            mode,
+            gid,
+        });
+
+        let mut claimed = vec![match &endpoint {
+            Endpoint::Serial { device, .. } => format!("device {}", device),
+            Endpoint::Pty(pty) => format!("link {}", pty.link_path.display()),
+            Endpoint::Tcp { listen } => format!("address {}", listen),
+            Endpoint::Unix { path } => format!("socket {}", path.display()),
+        }];
+
+        if let Some(log) = &log {
+            claimed.push(format!("log file {}", log.path.display()));
+        }
+
+        if let Some(mirror) = &mirror {
+            claimed.push(format!("link {}", mirror.link_path.display()));
+        }
+
+        for resource in claimed {
+            if let Some((other, other_line)) = resources.get(&resource) {
+                problem(format!(
+                    "{} is already used by [{}] on line {}",
+                    resource, other, other_line
+                ));
+            } else {
+                resources.insert(resource, (name.clone(), line));
+            }
+        }
+
+        channels.push(ChannelConfig {
+            name,
+            id: entry.id,
+            endpoint,
+            coalesce: CoalesceSettings {
+                max_bytes: entry.coalesce_bytes,
+                max_latency: Duration::from_millis(entry.coalesce_ms),
+            },
+            queue: QueueSettings {
+                priority: entry.priority,
+                weight: entry.weight,
+                capacity: entry.queue_size,
+                policy: match entry.when_full {
+                    WhenFull::Block => DropPolicy::Block,
+                    WhenFull::DropOldest => DropPolicy::DropOldest,
+                    WhenFull::DropNewest => DropPolicy::DropNewest,
+                },
+            },
+            compress: entry.compress,
+            log,
+            mirror,
+        });
+    }
+
+    if problems.is_empty() {
+        Ok(channels)
+    } else {
+        Err(problems)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate a config file and exit
+    CheckConfig {
+        #[arg(required = true)]
+        config: String,
+    },
+    /// Print the statistics of a running instance started with --stats-socket
+    Stats {
+        #[arg(required = true)]
+        socket: PathBuf,
+    },
+    /// Feed the data of one channel from a capture into a virtual serial port
+    Replay {
+        #[arg(required = true)]
+        capture: PathBuf,
+
+        /// Id of the channel to replay
+        #[arg(long)]
+        channel: u8,
+
+        /// Replay what was sent to the peer instead of what was received from it
+        #[arg(long, default_value_t = false)]
+        sent: bool,
+
+        /// Where to link the virtual serial port
+        #[arg(long, default_value = "/tmp/vtty/replay")]
+        link: PathBuf,
+
+        /// Start right away instead of waiting for the client to send something.
+        /// Data written before the client opened the port may be lost.
+        #[arg(long, default_value_t = false)]
+        now: bool,
+
+        /// Write the data as fast as possible instead of with the recorded timing
+        #[arg(long, default_value_t = false)]
+        fast: bool,
+    },
 }
 
 #[derive(Parser, Debug)]
 #[command(
     name = "serial-multiplexer",
     about = "Send multiple serial devices over a single serial",
-    version = "0.1"
+    version = "0.1",
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
 )]
 pub struct Args {
-    #[arg(long, default_value_t = false)]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Use virtual ports for entries without a `kind`
+    #[arg(
+        long,
+        global = true,
+        default_value_t = false,
+        conflicts_with = "with_real_ports"
+    )]
     pub with_virtual_ports: bool,
 
-    #[arg(long, default_value_t = false)]
+    /// Use real serial ports for entries without a `kind`
+    #[arg(long, global = true, default_value_t = false)]
     pub with_real_ports: bool,
 
-    #[arg(required = true)]
-    pub device: String,
+    /// Directory virtual ports are linked in, for entries without a `link_dir` [default: <temp dir>/vtty]
+    #[arg(long, global = true)]
+    pub link_dir: Option<PathBuf>,
+
+    /// Permissions of virtual ports in octal, e.g. 660, for entries without a `mode`
+    #[arg(long, global = true, value_parser = parse_mode)]
+    pub pty_mode: Option<u32>,
+
+    /// Group name or id owning virtual ports, for entries without a `group`
+    #[arg(long, global = true)]
+    pub pty_group: Option<String>,
 
     #[arg(required = true)]
-    pub config: String,
+    pub device: Option<String>,
 
-    #[arg(long, default_value_t = 115200)]
+    /// Required unless running with --role host
+    pub config: Option<String>,
+
+    /// Announce channels to the peer, or create virtual ports for the channels it announces
+    #[arg(long, value_enum, requires = "control")]
+    pub role: Option<Role>,
+
+    #[arg(long, default_value_t = DEFAULT_BAUD_RATE)]
     pub baud: u32,
+
+    /// Acknowledge and retransmit frames per channel. Both sides must enable this.
+    #[arg(long, default_value_t = false)]
+    pub reliable: bool,
+
+    /// Maximum number of unacknowledged frames per channel in reliable mode
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u8).range(1..=MAX_WINDOW_SIZE as i64))]
+    pub window_size: u8,
+
+    /// Time in milliseconds before unacknowledged frames are retransmitted in reliable mode
+    #[arg(long, default_value_t = 250)]
+    pub retransmit_timeout_ms: u64,
+
+    /// Allow frames above 255 bytes by sending lengths as varints. Both sides must enable this.
+    #[arg(long, global = true, default_value_t = false)]
+    pub large_frames: bool,
+
+    /// Exchange link status with the peer on a control channel. Both sides must enable this.
+    #[arg(long, global = true, default_value_t = false)]
+    pub control: bool,
+
+    /// Encrypt and authenticate the link with the hex key in this file. Both sides need the same key.
+    #[arg(long, global = true)]
+    pub psk_file: Option<PathBuf>,
+
+    /// Serve per-channel statistics on this Unix socket, for the `stats` subcommand
+    #[arg(long)]
+    pub stats_socket: Option<PathBuf>,
+
+    /// Serve statistics in Prometheus format over HTTP on this address, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    pub prometheus: Option<String>,
+
+    /// Record every frame on the multiplexed link to this pcapng file
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
+
+    /// Time in milliseconds between keepalive pings on the control channel
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive_interval_ms: u64,
+}
+
+impl Args {
+    pub fn defaults(&self) -> ConfigDefaults {
+        let kind = if self.with_real_ports {
+            Some(ChannelKind::Serial)
+        } else if self.with_virtual_ports {
+            Some(ChannelKind::Pty)
+        } else {
+            None
+        };
+
+        ConfigDefaults {
+            kind,
+            link_dir: self.link_dir.clone().unwrap_or_else(default_link_dir),
+            pty_mode: self.pty_mode,
+            pty_group: self.pty_group.clone(),
+            control: self.control,
+            max_payload_len: self.max_payload_len(),
+        }
+    }
+
+    /// Most data a frame takes with the given link options
+    pub fn max_payload_len(&self) -> usize {
+        let length = if self.large_frames {
+            LengthEncoding::Varint
+        } else {
+            LengthEncoding::Byte
+        };
+
+        match self.psk_file {
+            Some(_) => length.max_payload_len() - SEAL_OVERHEAD,
+            None => length.max_payload_len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_multiplexer::frame::MAX_VARINT_PAYLOAD_LEN;
+
+    use super::*;
+
+    fn defaults() -> ConfigDefaults {
+        ConfigDefaults {
+            kind: None,
+            link_dir: PathBuf::from("/tmp/vtty-test"),
+            pty_mode: None,
+            pty_group: None,
+            control: true,
+            max_payload_len: MAX_PAYLOAD_LEN,
+        }
+    }
+
+    fn problems(text: &str) -> Vec<String> {
+        match load_config(text, &defaults()) {
+            Ok(_) => vec![],
+            Err(problems) => problems.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn valid_config_loads() {
+        let channels = load_config(
+            r#"
+[bed]
+kind = "pty"
+id = 2
+
+[console]
+kind = "tcp"
+listen = "127.0.0.1:5000"
+id = 3
+"#,
+            &defaults(),
+        )
+        .ok()
+        .unwrap();
+
+        let ids: Vec<_> = channels.iter().map(|channel| channel.id).collect();
+        assert_eq!(ids, [2, 3]);
+    }
+
+    #[test]
+    fn duplicate_id_is_rejected() {
+        let problems = problems(
+            r#"[a]
+kind = "pty"
+id = 1
+
+[b]
+kind = "pty"
+id = 1
+"#,
+        );
+
+        assert_eq!(
+            problems,
+            ["line 5: [b] id 1 is already used by [a] on line 1"]
+        );
+    }
+
+    #[test]
+    fn reserved_id_is_rejected() {
+        let problems = problems(
+            r#"[a]
+kind = "pty"
+id = 240
+"#,
+        );
+
+        assert_eq!(
+            problems,
+            ["line 1: [a] id 240 is reserved for control messages, use an id below 240"]
+        );
+    }
+
+    #[test]
+    fn shared_device_is_rejected() {
+        let problems = problems(
+            r#"[a]
+device_path = "/dev/null"
+kind = "serial"
+id = 1
+
+[b]
+device_path = "/dev/null"
+kind = "serial"
+id = 2
+"#,
+        );
+
+        assert_eq!(
+            problems,
+            ["line 6: [b] device /dev/null is already used by [a] on line 1"]
+        );
+    }
+
+    #[test]
+    fn shared_address_is_rejected() {
+        let problems = problems(
+            r#"[a]
+kind = "tcp"
+listen = "127.0.0.1:5000"
+id = 1
+
+[b]
+kind = "tcp"
+listen = "127.0.0.1:5000"
+id = 2
+"#,
+        );
+
+        assert_eq!(
+            problems,
+            ["line 6: [b] address 127.0.0.1:5000 is already used by [a] on line 1"]
+        );
+    }
+
+    #[test]
+    fn shared_link_is_rejected() {
+        let problems = problems(
+            r#"[a]
+kind = "tcp"
+listen = "127.0.0.1:5000"
+mirror = "b"
+id = 1
+
+[b]
+kind = "pty"
+id = 2
+"#,
+        );
+
+        assert_eq!(
+            problems,
+            ["line 7: [b] link /tmp/vtty-test/b is already used by [a] on line 1"]
+        );
+    }
+
+    #[test]
+    fn missing_exact_device_is_accepted() {
+        let problems = problems(
+            r#"[a]
+device_path = "/dev/serial/by-id/usb-not-plugged-in"
+kind = "serial"
+id = 1
+"#,
+        );
+
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn large_coalesce_needs_large_frames() {
+        let text = r#"[a]
+kind = "pty"
+coalesce_bytes = 4096
+id = 1
+"#;
+
+        assert_eq!(
+            problems(text),
+            ["line 1: [a] coalesce_bytes must be between 1 and 255, more needs --large-frames"]
+        );
+
+        let defaults = ConfigDefaults {
+            max_payload_len: MAX_VARINT_PAYLOAD_LEN,
+            ..defaults()
+        };
+        assert!(load_config(text, &defaults).is_ok());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let problems = problems(
+            r#"[a]
+id = 1
+
+[b]
+kind = "tcp"
+id = 250
+"#,
+        );
+
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("line 1: [a] no kind given"));
+        assert!(problems[1].contains("reserved"));
+        assert!(problems[2].contains("'listen'"));
+    }
 }
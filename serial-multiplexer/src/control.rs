@@ -0,0 +1,892 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::channel::{Channel, ChannelState, DataBlock, FIRST_RESERVED_ID};
+use crate::line::LineState;
+use crate::multiplexer::ChannelFactory;
+use crate::poll::Waker;
+use crate::scheduler::MainBus;
+
+/// Channel id carrying [`ControlMessage`]s between the two ends of the link.
+pub const CONTROL_CHANNEL_ID: u8 = 0xFF;
+pub const PROTOCOL_VERSION: u8 = 1;
+// Oldest version we can talk to. Peers below it are ignored.
+const MIN_PROTOCOL_VERSION: u8 = 1;
+
+// The peer is considered gone after this many keepalive intervals without hearing from it
+const KEEPALIVE_MISSES: u32 = 3;
+
+// How often the local channels are checked for line setting changes.
+// Short, because a DTR toggle is used to reset the device on the other end. Ptys don't report
+// termios changes, so a hang up shorter than this can still be missed.
+const LINE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How long a hang up is held before the lines are raised again, however soon the client raised
+// them, so the device on the other end sees the pulse
+const MIN_HANG_UP: Duration = Duration::from_millis(100);
+
+const TYPE_HELLO: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_PONG: u8 = 3;
+const TYPE_CHANNEL_OPENED: u8 = 4;
+const TYPE_CHANNEL_CLOSED: u8 = 5;
+const TYPE_BAUD_RATE: u8 = 6;
+const TYPE_LINE_STATE: u8 = 7;
+const TYPE_CHANNEL_TABLE: u8 = 8;
+const TYPE_COMPRESSION: u8 = 9;
+
+/// Messages about the link itself. Each message is sent as a single frame on the control channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Sent on startup. `reply` asks the peer to answer with its own hello.
+    Hello {
+        version: u8,
+        reply: bool,
+        channels: Vec<u8>,
+    },
+    Ping {
+        token: u32,
+    },
+    Pong {
+        token: u32,
+    },
+    ChannelOpened {
+        id: u8,
+    },
+    ChannelClosed {
+        id: u8,
+    },
+    BaudRate {
+        id: u8,
+        baud_rate: u32,
+    },
+    /// The client of a virtual channel changed its termios or modem lines
+    LineState {
+        id: u8,
+        state: LineState,
+    },
+    /// Ids and names of the sender's channels, sent along with its hello when announcing.
+    /// Large tables are sent in several messages.
+    ChannelTable {
+        channels: Vec<(u8, String)>,
+    },
+    /// Channels the sender wants compressed, sent along with its hello.
+    /// Data on a channel is compressed once both ends asked for it.
+    Compression {
+        channels: Vec<u8>,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("Empty control message")]
+    Empty,
+    #[error("Unknown control message type {0}")]
+    UnknownType(u8),
+    #[error("Control message of type {0} is truncated")]
+    Truncated(u8),
+    #[error("Channel name is not valid UTF-8")]
+    InvalidName,
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+impl ControlMessage {
+    /// Protocol version that introduced the message. Peers speaking an older version don't get it.
+    pub fn version(&self) -> u8 {
+        match self {
+            ControlMessage::Hello { .. }
+            | ControlMessage::Ping { .. }
+            | ControlMessage::Pong { .. }
+            | ControlMessage::ChannelOpened { .. }
+            | ControlMessage::ChannelClosed { .. }
+            | ControlMessage::BaudRate { .. }
+            | ControlMessage::LineState { .. }
+            | ControlMessage::ChannelTable { .. }
+            | ControlMessage::Compression { .. } => 1,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ControlMessage::Hello {
+                version,
+                reply,
+                channels,
+            } => {
+                let mut buff = vec![TYPE_HELLO, *version, *reply as u8, channels.len() as u8];
+                buff.extend_from_slice(channels);
+                buff
+            }
+            ControlMessage::Ping { token } => {
+                let mut buff = vec![TYPE_PING];
+                buff.extend_from_slice(&token.to_be_bytes());
+                buff
+            }
+            ControlMessage::Pong { token } => {
+                let mut buff = vec![TYPE_PONG];
+                buff.extend_from_slice(&token.to_be_bytes());
+                buff
+            }
+            ControlMessage::ChannelOpened { id } => vec![TYPE_CHANNEL_OPENED, *id],
+            ControlMessage::ChannelClosed { id } => vec![TYPE_CHANNEL_CLOSED, *id],
+            ControlMessage::BaudRate { id, baud_rate } => {
+                let mut buff = vec![TYPE_BAUD_RATE, *id];
+                buff.extend_from_slice(&baud_rate.to_be_bytes());
+                buff
+            }
+            ControlMessage::LineState { id, state } => {
+                let mut buff = vec![TYPE_LINE_STATE, *id];
+                buff.extend_from_slice(&state.encode());
+                buff
+            }
+            ControlMessage::ChannelTable { channels } => {
+                // [count] then [id][name length][name] per channel, names are cut to 255 bytes
+                let mut buff = vec![TYPE_CHANNEL_TABLE, channels.len() as u8];
+
+                for (id, name) in channels {
+                    let name = &name.as_bytes()[..name.floor_char_boundary(u8::MAX as usize)];
+                    buff.push(*id);
+                    buff.push(name.len() as u8);
+                    buff.extend_from_slice(name);
+                }
+
+                buff
+            }
+            ControlMessage::Compression { channels } => {
+                let mut buff = vec![TYPE_COMPRESSION, channels.len() as u8];
+                buff.extend_from_slice(channels);
+                buff
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ControlError> {
+        let (message_type, body) = bytes.split_first().ok_or(ControlError::Empty)?;
+        let truncated = ControlError::Truncated(*message_type);
+
+        let message = match *message_type {
+            TYPE_HELLO => {
+                let (header, channels) = body.split_at_checked(3).ok_or(truncated)?;
+
+                if channels.len() != header[2] as usize {
+                    return Err(ControlError::Truncated(*message_type));
+                }
+
+                ControlMessage::Hello {
+                    version: header[0],
+                    reply: header[1] != 0,
+                    channels: channels.to_vec(),
+                }
+            }
+            TYPE_PING => ControlMessage::Ping {
+                token: read_u32(body).ok_or(truncated)?,
+            },
+            TYPE_PONG => ControlMessage::Pong {
+                token: read_u32(body).ok_or(truncated)?,
+            },
+            TYPE_CHANNEL_OPENED => ControlMessage::ChannelOpened {
+                id: *body.first().ok_or(truncated)?,
+            },
+            TYPE_CHANNEL_CLOSED => ControlMessage::ChannelClosed {
+                id: *body.first().ok_or(truncated)?,
+            },
+            TYPE_BAUD_RATE => ControlMessage::BaudRate {
+                id: *body.first().ok_or(truncated)?,
+                baud_rate: read_u32(&body[1..]).ok_or(ControlError::Truncated(*message_type))?,
+            },
+            TYPE_LINE_STATE => ControlMessage::LineState {
+                id: *body.first().ok_or(truncated)?,
+                state: LineState::decode(&body[1..])
+                    .ok_or(ControlError::Truncated(*message_type))?,
+            },
+            TYPE_CHANNEL_TABLE => {
+                let (count, mut rest) = body.split_first().ok_or(truncated)?;
+                let mut channels = vec![];
+
+                for _ in 0..*count {
+                    let [id, len, tail @ ..] = rest else {
+                        return Err(ControlError::Truncated(*message_type));
+                    };
+                    let (name, tail) = tail
+                        .split_at_checked(*len as usize)
+                        .ok_or(ControlError::Truncated(*message_type))?;
+                    let name =
+                        String::from_utf8(name.to_vec()).map_err(|_| ControlError::InvalidName)?;
+
+                    channels.push((*id, name));
+                    rest = tail;
+                }
+
+                ControlMessage::ChannelTable { channels }
+            }
+            TYPE_COMPRESSION => {
+                let (count, channels) = body.split_first().ok_or(truncated)?;
+
+                if channels.len() != *count as usize {
+                    return Err(ControlError::Truncated(*message_type));
+                }
+
+                ControlMessage::Compression {
+                    channels: channels.to_vec(),
+                }
+            }
+            other => return Err(ControlError::UnknownType(other)),
+        };
+
+        Ok(message)
+    }
+}
+
+// Cuts a channel table into messages of at most `max_len` bytes. A long table doesn't fit one
+// frame, especially without --large-frames. Names are shortened so a single entry always fits.
+fn channel_tables(channels: Vec<(u8, String)>, max_len: usize) -> Vec<ControlMessage> {
+    // Message type and count, then id and name length per entry
+    const HEADER_LEN: usize = 2;
+    const ENTRY_HEADER_LEN: usize = 2;
+
+    let max_name_len = max_len
+        .saturating_sub(HEADER_LEN + ENTRY_HEADER_LEN)
+        .min(u8::MAX as usize);
+    let mut tables = vec![];
+    let mut table = vec![];
+    let mut len = HEADER_LEN;
+
+    for (id, mut name) in channels {
+        name.truncate(name.floor_char_boundary(max_name_len));
+        let entry_len = ENTRY_HEADER_LEN + name.len();
+
+        if len + entry_len > max_len || table.len() == u8::MAX as usize {
+            tables.push(ControlMessage::ChannelTable {
+                channels: std::mem::take(&mut table),
+            });
+            len = HEADER_LEN;
+        }
+
+        len += entry_len;
+        table.push((id, name));
+    }
+
+    if !table.is_empty() {
+        tables.push(ControlMessage::ChannelTable { channels: table });
+    }
+
+    tables
+}
+
+#[derive(Clone, Copy)]
+pub struct ControlSettings {
+    pub keepalive_interval: Duration,
+    /// Send the ids and names of our channels to the peer, see [`Multiplexer::with_channel_factory`](crate::Multiplexer::with_channel_factory)
+    pub announce_channels: bool,
+}
+
+/// What we know about the other end of the link.
+#[derive(Clone, Debug, Default)]
+pub struct LinkStatus {
+    pub peer_alive: bool,
+    /// Protocol version the peer announced in its hello
+    pub peer_version: Option<u8>,
+    /// Channels currently open on the peer
+    pub peer_channels: HashSet<u8>,
+    /// Last baud rate the peer reported per channel
+    pub peer_baud_rates: HashMap<u8, u32>,
+    /// Channels the peer asked to compress
+    pub peer_compressed: HashSet<u8>,
+    pub rtt: Option<Duration>,
+    pub last_seen: Option<Instant>,
+}
+
+impl LinkStatus {
+    /// Protocol version both ends speak, the lower of the two. None until the peer's hello.
+    pub fn version(&self) -> Option<u8> {
+        self.peer_version
+            .map(|version| version.min(PROTOCOL_VERSION))
+    }
+}
+
+/// Lets the application send notifications over the control channel while the multiplexer runs.
+#[derive(Clone)]
+pub struct ControlHandle {
+    sender: Sender<DataBlock>,
+    waker: Waker,
+}
+
+impl ControlHandle {
+    pub(crate) fn new(sender: Sender<DataBlock>, waker: Waker) -> Self {
+        ControlHandle { sender, waker }
+    }
+
+    pub fn notify(&self, id: u8, message: ControlMessage) {
+        let _ = self.sender.send(DataBlock {
+            id,
+            data: message.encode(),
+            compressed: false,
+        });
+        self.waker.wake();
+    }
+
+    pub fn notify_channel_open(&self, id: u8, open: bool) {
+        self.notify(id, channel_open_message(id, open));
+    }
+
+    pub fn notify_baud_rate(&self, id: u8, baud_rate: u32) {
+        self.notify(id, ControlMessage::BaudRate { id, baud_rate });
+    }
+}
+
+fn channel_open_message(id: u8, open: bool) -> ControlMessage {
+    if open {
+        ControlMessage::ChannelOpened { id }
+    } else {
+        ControlMessage::ChannelClosed { id }
+    }
+}
+
+// Runs the control channel as part of the multiplexer's event loop.
+// Messages come from the peer, as local notifications through a ControlHandle, or straight from the loop.
+pub(crate) struct ControlProcessor {
+    settings: ControlSettings,
+    status: Arc<Mutex<LinkStatus>>,
+    receiver: Receiver<DataBlock>,
+    // Creates local channels for the peer's channel table
+    factory: Option<ChannelFactory>,
+    open_channels: HashSet<u8>,
+    next_token: u32,
+    pending_ping: Option<(u32, Instant)>,
+    next_keepalive: Instant,
+    line_states: HashMap<u8, LineState>,
+    // Channels whose hang up is held until then
+    hang_ups: HashMap<u8, Instant>,
+    // None while no channel has line settings to watch
+    next_line_poll: Option<Instant>,
+    // Channels last reported as compressed
+    compressed: Vec<u8>,
+    // Largest message that fits in a frame
+    max_payload_len: usize,
+}
+
+impl ControlProcessor {
+    pub fn new(
+        settings: ControlSettings,
+        status: Arc<Mutex<LinkStatus>>,
+        receiver: Receiver<DataBlock>,
+        factory: Option<ChannelFactory>,
+        max_payload_len: usize,
+    ) -> Self {
+        ControlProcessor {
+            settings,
+            status,
+            receiver,
+            factory,
+            // Channels are reported open by the event loop once their transport is up
+            open_channels: HashSet::new(),
+            next_token: 0,
+            pending_ping: None,
+            next_keepalive: Instant::now(),
+            line_states: HashMap::new(),
+            hang_ups: HashMap::new(),
+            next_line_poll: Some(Instant::now()),
+            compressed: vec![],
+            max_payload_len,
+        }
+    }
+
+    // Messages the peer's version doesn't have are left out. Until its hello only the ones every
+    // version has are sent.
+    fn send(&self, bus: &mut MainBus, message: ControlMessage) {
+        let version = self.status.lock().unwrap().version();
+
+        if message.version() > version.unwrap_or(MIN_PROTOCOL_VERSION) {
+            return;
+        }
+
+        bus.send(DataBlock {
+            id: CONTROL_CHANNEL_ID,
+            data: message.encode(),
+            compressed: false,
+        });
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.next_line_poll
+            .map_or(self.next_keepalive, |poll| poll.min(self.next_keepalive))
+    }
+
+    // Sends a hello, followed by our channel table when announcing and the channels we want compressed
+    fn send_hello(&self, bus: &mut MainBus, channels: &[ChannelState], reply: bool) {
+        let mut open_channels: Vec<u8> = self.open_channels.iter().copied().collect();
+        open_channels.sort();
+
+        let hello = ControlMessage::Hello {
+            version: PROTOCOL_VERSION,
+            reply,
+            channels: open_channels,
+        };
+
+        self.send(bus, hello);
+
+        if self.settings.announce_channels {
+            let table = channels
+                .iter()
+                .filter_map(|channel| Some((channel.id(), channel.channel.name.clone()?)))
+                .collect();
+
+            for message in channel_tables(table, self.max_payload_len) {
+                self.send(bus, message);
+            }
+        }
+
+        let compressed: Vec<u8> = channels
+            .iter()
+            .filter(|channel| channel.channel.compress)
+            .map(|channel| channel.id())
+            .collect();
+
+        // Peers without compression support would only complain about the message
+        if !compressed.is_empty() {
+            self.send(
+                bus,
+                ControlMessage::Compression {
+                    channels: compressed,
+                },
+            );
+        }
+    }
+
+    // Starts compressing the channels both ends asked to compress, and stops compressing the rest
+    fn update_compression(&mut self, peer_compressed: &HashSet<u8>, channels: &mut [ChannelState]) {
+        let mut compressed = vec![];
+
+        for channel in channels {
+            channel.compressing =
+                channel.channel.compress && peer_compressed.contains(&channel.id());
+
+            if channel.compressing {
+                compressed.push(channel.id());
+            }
+        }
+
+        compressed.sort();
+
+        // Every hello resets compression until the peer's compression message, only report real changes
+        if !peer_compressed.is_empty() && compressed != self.compressed {
+            println!("Compressing channels {:?}", compressed);
+            self.compressed = compressed;
+        }
+    }
+
+    // Creates a local channel for every announced one we don't have yet
+    fn create_channels(
+        &self,
+        announced: Vec<(u8, String)>,
+        channels: &[ChannelState],
+    ) -> Vec<Channel> {
+        let Some(factory) = &self.factory else {
+            return vec![];
+        };
+
+        let mut created = vec![];
+
+        for (id, name) in announced {
+            if id >= FIRST_RESERVED_ID || channels.iter().any(|channel| channel.id() == id) {
+                continue;
+            }
+
+            match factory(id, &name) {
+                Ok(channel) => {
+                    println!("Created channel {} ({}) announced by the peer", id, name);
+                    created.push(channel);
+                }
+                Err(e) => eprintln!("Failed to create channel {} ({}): {}", id, name, e),
+            }
+        }
+
+        created
+    }
+
+    // Sends the settings of every channel that changed since the last poll.
+    // The first state seen is only recorded, the real port on the other end keeps its configured settings until the client changes something.
+    // Returns whether any channel is watched.
+    fn poll_line_states(
+        &mut self,
+        now: Instant,
+        bus: &mut MainBus,
+        channels: &mut [ChannelState],
+    ) -> bool {
+        let mut watched = false;
+
+        for channel in channels {
+            let state = match channel.channel.transport.line_state() {
+                Ok(Some(state)) => state,
+                Ok(None) => continue,
+                // Only watched channels read their line settings, this one is just closed
+                Err(e) => {
+                    watched = true;
+                    eprintln!(
+                        "Failed to read line settings of channel {}: {}",
+                        channel.id(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            watched = true;
+
+            // The raise is reported by a later poll, once the hang up was held long enough
+            if state.baud_rate != 0
+                && self
+                    .hang_ups
+                    .get(&channel.id())
+                    .is_some_and(|&until| now < until)
+            {
+                continue;
+            }
+
+            let previous = self.line_states.insert(channel.id(), state);
+
+            if previous.is_some_and(|previous| previous != state) {
+                if state.baud_rate == 0 {
+                    self.hang_ups.insert(channel.id(), now + MIN_HANG_UP);
+                }
+
+                #[cfg(debug_assertions)]
+                println!(
+                    "Channel {} line settings changed: {:?}",
+                    channel.id(),
+                    state
+                );
+
+                let message = ControlMessage::LineState {
+                    id: channel.id(),
+                    state,
+                };
+
+                self.send(bus, message);
+            }
+        }
+
+        watched
+    }
+
+    fn apply_line_state(&self, id: u8, state: &LineState, channels: &mut [ChannelState]) {
+        let Some(channel) = channels.iter_mut().find(|channel| channel.id() == id) else {
+            eprintln!("Peer changed line settings of unknown channel {}", id);
+            return;
+        };
+
+        if let Err(e) = channel.channel.transport.apply_line_state(state) {
+            eprintln!("Failed to apply line settings to channel {}: {}", id, e);
+        }
+    }
+
+    /// Sends keepalives and line setting changes that are due.
+    pub fn on_timer(&mut self, now: Instant, bus: &mut MainBus, channels: &mut [ChannelState]) {
+        if self.next_line_poll.is_some_and(|poll| now >= poll) {
+            let watched = self.poll_line_states(now, bus, channels);
+            self.next_line_poll = watched.then_some(now + LINE_POLL_INTERVAL);
+        }
+
+        if now >= self.next_keepalive {
+            self.next_keepalive = now + self.settings.keepalive_interval;
+            self.check_peer_alive(now);
+
+            let known = self.status.lock().unwrap().peer_version.is_some();
+            // Keep asking until the peer answers, the first hello may have been lost
+            if known {
+                let token = self.next_token;
+                self.next_token = self.next_token.wrapping_add(1);
+                self.pending_ping = Some((token, now));
+                self.send(bus, ControlMessage::Ping { token });
+            } else {
+                self.send_hello(bus, channels, true);
+            }
+        }
+    }
+
+    /// Forwards notifications sent through a [`ControlHandle`].
+    pub fn on_notifications(&mut self, bus: &mut MainBus) {
+        while let Ok(block) = self.receiver.try_recv() {
+            match ControlMessage::decode(&block.data) {
+                Ok(message) => self.notify(bus, message),
+                Err(e) => eprintln!("Dropping control message: {}", e),
+            }
+        }
+    }
+
+    /// Tells the peer a local channel opened or closed.
+    pub fn notify_channel_open(&mut self, bus: &mut MainBus, id: u8, open: bool) {
+        self.notify(bus, channel_open_message(id, open));
+    }
+
+    fn notify(&mut self, bus: &mut MainBus, message: ControlMessage) {
+        match message {
+            ControlMessage::ChannelOpened { id } => {
+                self.open_channels.insert(id);
+            }
+            ControlMessage::ChannelClosed { id } => {
+                self.open_channels.remove(&id);
+            }
+            _ => {}
+        }
+
+        self.send(bus, message);
+    }
+
+    /// Handles a message from the peer. Returns the channels created for its channel table.
+    pub fn on_message(
+        &mut self,
+        data: &[u8],
+        bus: &mut MainBus,
+        channels: &mut [ChannelState],
+    ) -> Vec<Channel> {
+        let message = match ControlMessage::decode(data) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Dropping control message: {}", e);
+                return vec![];
+            }
+        };
+
+        self.mark_peer_seen();
+
+        match message {
+            ControlMessage::Hello {
+                version,
+                reply,
+                channels: peer_channels,
+            } => {
+                if version < MIN_PROTOCOL_VERSION {
+                    eprintln!(
+                        "Peer speaks control protocol version {}, we need at least {}. Ignoring it.",
+                        version, MIN_PROTOCOL_VERSION
+                    );
+                    return vec![];
+                }
+
+                if version != PROTOCOL_VERSION {
+                    eprintln!(
+                        "Peer speaks control protocol version {}, we speak {}. Using version {}.",
+                        version,
+                        PROTOCOL_VERSION,
+                        version.min(PROTOCOL_VERSION)
+                    );
+                }
+
+                let missing: Vec<u8> = channels
+                    .iter()
+                    .map(|channel| channel.id())
+                    .filter(|id| !peer_channels.contains(id))
+                    .collect();
+
+                println!("Peer has channels {:?} open", peer_channels);
+
+                if !missing.is_empty() {
+                    eprintln!("Channels {:?} are not open on the peer", missing);
+                }
+
+                let mut status = self.status.lock().unwrap();
+                status.peer_version = Some(version);
+                status.peer_channels = peer_channels.into_iter().collect();
+                // The peer may have restarted with other settings, its compression message follows
+                status.peer_compressed.clear();
+                drop(status);
+
+                self.update_compression(&HashSet::new(), channels);
+
+                if reply {
+                    self.send_hello(bus, channels, false);
+                }
+            }
+            ControlMessage::Ping { token } => self.send(bus, ControlMessage::Pong { token }),
+            ControlMessage::Pong { token } => {
+                if let Some((pending, sent_at)) = self.pending_ping
+                    && pending == token
+                {
+                    let rtt = sent_at.elapsed();
+                    self.pending_ping = None;
+
+                    #[cfg(debug_assertions)]
+                    println!("Link RTT: {}ms", rtt.as_millis());
+
+                    self.status.lock().unwrap().rtt = Some(rtt);
+                }
+            }
+            ControlMessage::ChannelOpened { id } => {
+                println!("Peer opened channel {}", id);
+                self.status.lock().unwrap().peer_channels.insert(id);
+            }
+            ControlMessage::ChannelClosed { id } => {
+                println!("Peer closed channel {}", id);
+                self.status.lock().unwrap().peer_channels.remove(&id);
+            }
+            ControlMessage::BaudRate { id, baud_rate } => {
+                println!("Peer changed baud rate of channel {} to {}", id, baud_rate);
+                self.status
+                    .lock()
+                    .unwrap()
+                    .peer_baud_rates
+                    .insert(id, baud_rate);
+            }
+            ControlMessage::LineState { id, state } => {
+                println!("Peer changed line settings of channel {}: {:?}", id, state);
+                self.apply_line_state(id, &state, channels);
+            }
+            ControlMessage::ChannelTable {
+                channels: announced,
+            } => {
+                println!("Peer announced channels {:?}", announced);
+                let created = self.create_channels(announced, channels);
+
+                // The new channels may have line settings to watch
+                if !created.is_empty() {
+                    self.next_line_poll = Some(Instant::now());
+                }
+
+                return created;
+            }
+            ControlMessage::Compression {
+                channels: compressed,
+            } => {
+                let compressed: HashSet<u8> = compressed.into_iter().collect();
+                self.update_compression(&compressed, channels);
+                self.status.lock().unwrap().peer_compressed = compressed;
+            }
+        }
+
+        vec![]
+    }
+
+    fn mark_peer_seen(&self) {
+        let mut status = self.status.lock().unwrap();
+
+        if !status.peer_alive {
+            println!("Peer is alive");
+        }
+
+        status.peer_alive = true;
+        status.last_seen = Some(Instant::now());
+    }
+
+    fn check_peer_alive(&self, now: Instant) {
+        let mut status = self.status.lock().unwrap();
+        let deadline = self.settings.keepalive_interval * KEEPALIVE_MISSES;
+
+        if status.peer_alive
+            && status
+                .last_seen
+                .is_none_or(|last_seen| now.duration_since(last_seen) > deadline)
+        {
+            eprintln!(
+                "Peer has not responded for {}ms, assuming it is gone",
+                deadline.as_millis()
+            );
+            status.peer_alive = false;
+            status.rtt = None;
+            // Ask for a fresh hello once it comes back, it may have restarted
+            status.peer_version = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::stats::Stats;
+
+    fn processor() -> (ControlProcessor, Arc<Mutex<LinkStatus>>, MainBus) {
+        let status = Arc::new(Mutex::new(LinkStatus::default()));
+        let settings = ControlSettings {
+            keepalive_interval: Duration::from_secs(1),
+            announce_channels: false,
+        };
+        let (_, receiver) = mpsc::channel();
+        let processor = ControlProcessor::new(settings, status.clone(), receiver, None, 255);
+
+        (processor, status, MainBus::new(Arc::new(Stats::default())))
+    }
+
+    fn hello(version: u8) -> Vec<u8> {
+        ControlMessage::Hello {
+            version,
+            reply: true,
+            channels: vec![],
+        }
+        .encode()
+    }
+
+    #[test]
+    fn newer_peer_uses_our_version() {
+        let (mut processor, status, mut bus) = processor();
+
+        processor.on_message(&hello(PROTOCOL_VERSION + 1), &mut bus, &mut []);
+
+        assert_eq!(status.lock().unwrap().version(), Some(PROTOCOL_VERSION));
+        let reply = bus.next(|_| true).unwrap();
+        assert!(matches!(
+            ControlMessage::decode(&reply.data).unwrap(),
+            ControlMessage::Hello {
+                version: PROTOCOL_VERSION,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn older_peer_is_ignored() {
+        let (mut processor, status, mut bus) = processor();
+
+        processor.on_message(&hello(MIN_PROTOCOL_VERSION - 1), &mut bus, &mut []);
+
+        assert_eq!(status.lock().unwrap().version(), None);
+        assert!(bus.is_empty());
+    }
+
+    #[test]
+    fn channel_table_is_split_to_fit_frames() {
+        let channels: Vec<(u8, String)> = (0..20)
+            .map(|id| {
+                let name = format!("usb-Klipper_stm32f446xx_{:02}3F0012345678-if00", id);
+                (id, name)
+            })
+            .collect();
+
+        // No --large-frames, with --psk-file
+        let tables = channel_tables(channels.clone(), 235);
+        assert!(tables.len() > 1);
+
+        let mut received = vec![];
+        for table in tables {
+            let encoded = table.encode();
+            assert!(encoded.len() <= 235);
+
+            match ControlMessage::decode(&encoded).unwrap() {
+                ControlMessage::ChannelTable { channels } => received.extend(channels),
+                other => panic!("Decoded {:?} from a channel table", other),
+            }
+        }
+
+        assert_eq!(received, channels);
+    }
+
+    #[test]
+    fn long_channel_names_are_shortened_to_fit() {
+        let name = "ä".repeat(200);
+        let tables = channel_tables(vec![(1, name)], 64);
+
+        let [ControlMessage::ChannelTable { channels }] = tables.as_slice() else {
+            panic!("Expected a single table, got {:?}", tables);
+        };
+        assert!(tables[0].encode().len() <= 64);
+        assert!(channels[0].1.chars().all(|c| c == 'ä'));
+    }
+}
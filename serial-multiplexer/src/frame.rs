@@ -0,0 +1,619 @@
+use thiserror::Error;
+
+// Plain frame layout:
+// [id][len][payload; len]
+//
+// Plain frames have no flags. Compressed data is wrapped in a frame for the reserved id
+// COMPRESSED_ID instead, with the channel id as the first payload byte:
+// [COMPRESSED_ID][len][id][compressed payload; len - 1]
+//
+// Checked frame layout (used in reliable mode):
+// [MAGIC][id][flags][seq][len][payload; len][crc16 hi][crc16 lo]
+// The CRC covers everything between the magic byte and the CRC itself.
+//
+// `len` is a single byte, or a 1-2 byte varint with the varint length encoding.
+// Checked frames with varint lengths add a header check byte after `len`, the low byte of
+// the CRC over id to len. Otherwise a corrupted length could stall the decoder until up to
+// 16K bytes arrived before the CRC shows the frame is bad.
+pub const FRAME_MAGIC: u8 = 0xA5;
+const PLAIN_HEADER_LEN: usize = 1;
+const CHECKED_HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+pub const MAX_PAYLOAD_LEN: usize = 255;
+// Largest payload a two byte varint can describe
+pub const MAX_VARINT_PAYLOAD_LEN: usize = 0x3FFF;
+// Longest encoded frame: checked, with a two byte varint length and the header check byte
+pub const MAX_FRAME_LEN: usize = CHECKED_HEADER_LEN + 2 + 1 + MAX_VARINT_PAYLOAD_LEN + CRC_LEN;
+
+pub const FLAG_ACK: u8 = 0x01;
+// Resets the receiving side of the channel; occupies one sequence number
+pub const FLAG_SYNC: u8 = 0x02;
+// Sent with an ACK when the receiver has no state for the channel (e.g. after a restart)
+pub const FLAG_NEED_SYNC: u8 = 0x04;
+// The payload is an LZ4 block, see `compress`
+pub const FLAG_COMPRESSED: u8 = 0x08;
+// Carries compressed frames in the plain format, which has no flags
+pub const COMPRESSED_ID: u8 = 0xFE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameFormat {
+    /// Bare id and length header. Cheap, but cannot detect corruption.
+    Plain,
+    /// Magic byte, flags, sequence number and CRC. Required for reliable mode.
+    Checked,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthEncoding {
+    /// One length byte, payloads up to 255 bytes.
+    #[default]
+    Byte,
+    /// Little-endian base-128 varint of one or two bytes, payloads up to 16383 bytes.
+    /// Lengths below 128 encode the same as with [`LengthEncoding::Byte`].
+    Varint,
+}
+
+impl LengthEncoding {
+    pub fn max_payload_len(self) -> usize {
+        match self {
+            LengthEncoding::Byte => MAX_PAYLOAD_LEN,
+            LengthEncoding::Varint => MAX_VARINT_PAYLOAD_LEN,
+        }
+    }
+
+    /// Longest encoded frame with this length encoding
+    pub fn max_frame_len(self) -> usize {
+        match self {
+            LengthEncoding::Byte => CHECKED_HEADER_LEN + 1 + MAX_PAYLOAD_LEN + CRC_LEN,
+            LengthEncoding::Varint => MAX_FRAME_LEN,
+        }
+    }
+
+    fn write(self, len: usize, buff: &mut Vec<u8>) {
+        match self {
+            LengthEncoding::Byte => buff.push(len as u8),
+            LengthEncoding::Varint if len < 0x80 => buff.push(len as u8),
+            LengthEncoding::Varint => {
+                buff.push((len & 0x7F) as u8 | 0x80);
+                buff.push((len >> 7) as u8);
+            }
+        }
+    }
+
+    // Returns the length and the number of bytes it took, or None if more bytes are needed
+    fn read(self, bytes: &[u8]) -> Result<Option<(usize, usize)>, FrameError> {
+        let Some(first) = bytes.first() else {
+            return Ok(None);
+        };
+
+        if self == LengthEncoding::Byte || first & 0x80 == 0 {
+            return Ok(Some((*first as usize, 1)));
+        }
+
+        let Some(second) = bytes.get(1) else {
+            return Ok(None);
+        };
+
+        if second & 0x80 != 0 {
+            return Err(FrameError::InvalidLength);
+        }
+
+        Ok(Some((
+            (*first & 0x7F) as usize | (*second as usize) << 7,
+            2,
+        )))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Received zero-length data for device {0}")]
+    ZeroLength(u8),
+    #[error("Payload of {0} bytes does not fit in a frame")]
+    PayloadTooLarge(usize),
+    #[error("Frame length is longer than two bytes")]
+    InvalidLength,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u8,
+    pub flags: u8,
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn data(id: u8, payload: Vec<u8>) -> Self {
+        Frame {
+            id,
+            flags: 0,
+            seq: 0,
+            payload,
+        }
+    }
+
+    /// Serializes the frame. In the plain format, sequence numbers are not sent and
+    /// [`FLAG_COMPRESSED`] is the only flag that is.
+    pub fn encode(
+        &self,
+        format: FrameFormat,
+        length: LengthEncoding,
+    ) -> Result<Vec<u8>, FrameError> {
+        let len = self.payload.len();
+        let wrapped = format == FrameFormat::Plain && self.flags & FLAG_COMPRESSED != 0;
+
+        if len + wrapped as usize > length.max_payload_len() {
+            return Err(FrameError::PayloadTooLarge(len));
+        }
+
+        let buff = match format {
+            FrameFormat::Plain if wrapped => {
+                let mut buff = Vec::with_capacity(PLAIN_HEADER_LEN + 3 + len);
+                buff.push(COMPRESSED_ID);
+                length.write(len + 1, &mut buff);
+                buff.push(self.id);
+                buff.extend_from_slice(&self.payload);
+                buff
+            }
+            FrameFormat::Plain => {
+                let mut buff = Vec::with_capacity(PLAIN_HEADER_LEN + 2 + len);
+                buff.push(self.id);
+                length.write(len, &mut buff);
+                buff.extend_from_slice(&self.payload);
+                buff
+            }
+            FrameFormat::Checked => {
+                let mut buff = Vec::with_capacity(CHECKED_HEADER_LEN + 2 + len + CRC_LEN);
+                buff.push(FRAME_MAGIC);
+                buff.push(self.id);
+                buff.push(self.flags);
+                buff.push(self.seq);
+                length.write(len, &mut buff);
+
+                if length == LengthEncoding::Varint {
+                    let header_check = crc16(&buff[1..]) as u8;
+                    buff.push(header_check);
+                }
+
+                buff.extend_from_slice(&self.payload);
+
+                let crc = crc16(&buff[1..]);
+                buff.extend_from_slice(&crc.to_be_bytes());
+                buff
+            }
+        };
+
+        Ok(buff)
+    }
+}
+
+// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Buffers raw bytes from the multiplexed link and cuts them into frames.
+///
+/// In the checked format garbage and corrupted frames are skipped by hunting for the next
+/// magic byte. The plain format has no way to resync by itself; the caller is expected to
+/// [`clear`](FrameDecoder::clear) the decoder (and the link) when it sees an error.
+pub struct FrameDecoder {
+    format: FrameFormat,
+    length: LengthEncoding,
+    buffer: Vec<u8>,
+    // Times garbage or a corrupted frame was skipped since the last `take_resyncs`
+    resyncs: u64,
+}
+
+impl FrameDecoder {
+    pub fn new(format: FrameFormat, length: LengthEncoding) -> Self {
+        FrameDecoder {
+            format,
+            length,
+            // Room for a whole frame and the start of the next one
+            buffer: Vec::with_capacity(2 * length.max_frame_len()),
+            resyncs: 0,
+        }
+    }
+
+    pub fn take_resyncs(&mut self) -> u64 {
+        std::mem::take(&mut self.resyncs)
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        match self.format {
+            FrameFormat::Plain => self.next_plain_frame(),
+            FrameFormat::Checked => Ok(self.next_checked_frame()),
+        }
+    }
+
+    fn next_plain_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        let Some(id) = self.buffer.first().copied() else {
+            return Ok(None);
+        };
+
+        let Some((len, len_bytes)) = self.length.read(&self.buffer[PLAIN_HEADER_LEN..])? else {
+            return Ok(None);
+        };
+
+        if len == 0 {
+            return Err(FrameError::ZeroLength(id));
+        }
+
+        let header_len = PLAIN_HEADER_LEN + len_bytes;
+
+        if self.buffer.len() < header_len + len {
+            return Ok(None);
+        }
+
+        let mut payload = self.buffer[header_len..header_len + len].to_vec();
+        self.buffer.drain(..header_len + len);
+
+        if id != COMPRESSED_ID {
+            return Ok(Some(Frame::data(id, payload)));
+        }
+
+        let id = payload.remove(0);
+
+        if payload.is_empty() {
+            return Err(FrameError::ZeroLength(id));
+        }
+
+        Ok(Some(Frame {
+            flags: FLAG_COMPRESSED,
+            ..Frame::data(id, payload)
+        }))
+    }
+
+    fn next_checked_frame(&mut self) -> Option<Frame> {
+        loop {
+            let start = match self.buffer.iter().position(|b| *b == FRAME_MAGIC) {
+                Some(start) => start,
+                None => {
+                    self.buffer.clear();
+                    return None;
+                }
+            };
+
+            if start > 0 {
+                eprintln!("Skipping {} bytes of garbage on multiplexed port", start);
+                self.buffer.drain(..start);
+                self.resyncs += 1;
+            }
+
+            if self.buffer.len() < CHECKED_HEADER_LEN {
+                return None;
+            }
+
+            let (len, len_bytes) = match self.length.read(&self.buffer[CHECKED_HEADER_LEN..]) {
+                Ok(Some(len)) => len,
+                Ok(None) => return None,
+                Err(e) => {
+                    eprintln!("{} on multiplexed port, resyncing...", e);
+                    self.buffer.drain(..1);
+                    self.resyncs += 1;
+                    continue;
+                }
+            };
+
+            let mut header_len = CHECKED_HEADER_LEN + len_bytes;
+
+            if self.length == LengthEncoding::Varint {
+                let header_check = self.buffer.get(header_len)?;
+
+                if crc16(&self.buffer[1..header_len]) as u8 != *header_check {
+                    eprintln!("Header check mismatch on multiplexed port, resyncing...");
+                    self.buffer.drain(..1);
+                    self.resyncs += 1;
+                    continue;
+                }
+
+                header_len += 1;
+            }
+
+            let total = header_len + len + CRC_LEN;
+
+            if self.buffer.len() < total {
+                return None;
+            }
+
+            let expected = crc16(&self.buffer[1..header_len + len]);
+            let actual = u16::from_be_bytes([self.buffer[total - 2], self.buffer[total - 1]]);
+
+            if expected != actual {
+                eprintln!("CRC mismatch on multiplexed port, resyncing...");
+                self.buffer.drain(..1);
+                self.resyncs += 1;
+                continue;
+            }
+
+            let frame = Frame {
+                id: self.buffer[1],
+                flags: self.buffer[2],
+                seq: self.buffer[3],
+                payload: self.buffer[header_len..header_len + len].to_vec(),
+            };
+
+            self.buffer.drain(..total);
+            return Some(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(format: FrameFormat, length: LengthEncoding, bytes: &[u8]) -> Vec<Frame> {
+        let mut decoder = FrameDecoder::new(format, length);
+        decoder.push(bytes);
+
+        let mut frames = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn crc_is_ccitt_false() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn varint_lengths() {
+        let cases: [(usize, &[u8]); 4] = [
+            (1, &[0x01]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0x80, 0x01]),
+            (MAX_VARINT_PAYLOAD_LEN, &[0xFF, 0x7F]),
+        ];
+
+        for (len, header) in cases {
+            let frame = Frame::data(1, vec![0; len]);
+            let bytes = frame
+                .encode(FrameFormat::Plain, LengthEncoding::Varint)
+                .unwrap();
+
+            assert_eq!(&bytes[1..1 + header.len()], header, "length {}", len);
+            assert_eq!(bytes.len(), 1 + header.len() + len);
+            assert_eq!(
+                decode_all(FrameFormat::Plain, LengthEncoding::Varint, &bytes),
+                [frame]
+            );
+        }
+    }
+
+    #[test]
+    fn payload_limits() {
+        let fits = |length, len| {
+            Frame::data(1, vec![0; len])
+                .encode(FrameFormat::Checked, length)
+                .is_ok()
+        };
+
+        assert!(fits(LengthEncoding::Byte, MAX_PAYLOAD_LEN));
+        assert!(!fits(LengthEncoding::Byte, MAX_PAYLOAD_LEN + 1));
+        assert!(fits(LengthEncoding::Varint, MAX_VARINT_PAYLOAD_LEN));
+        assert!(!fits(LengthEncoding::Varint, MAX_VARINT_PAYLOAD_LEN + 1));
+
+        // The channel id takes a byte of the wrapped payload
+        let compressed = Frame {
+            flags: FLAG_COMPRESSED,
+            ..Frame::data(1, vec![0; MAX_PAYLOAD_LEN])
+        };
+        assert!(matches!(
+            compressed.encode(FrameFormat::Plain, LengthEncoding::Byte),
+            Err(FrameError::PayloadTooLarge(MAX_PAYLOAD_LEN))
+        ));
+    }
+
+    #[test]
+    fn longest_frames() {
+        for length in [LengthEncoding::Byte, LengthEncoding::Varint] {
+            let frame = Frame::data(1, vec![0; length.max_payload_len()]);
+            let bytes = frame.encode(FrameFormat::Checked, length).unwrap();
+
+            assert_eq!(bytes.len(), length.max_frame_len());
+        }
+
+        assert_eq!(LengthEncoding::Varint.max_frame_len(), MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn three_byte_varint_is_rejected() {
+        let mut decoder = FrameDecoder::new(FrameFormat::Plain, LengthEncoding::Varint);
+        decoder.push(&[1, 0x80, 0x80, 0x01]);
+
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn checked_frame_layout() {
+        let frame = Frame {
+            id: 3,
+            flags: FLAG_SYNC,
+            seq: 7,
+            payload: vec![0x10, 0x20],
+        };
+
+        let bytes = frame
+            .encode(FrameFormat::Checked, LengthEncoding::Byte)
+            .unwrap();
+        let crc = crc16(&[3, FLAG_SYNC, 7, 2, 0x10, 0x20]).to_be_bytes();
+        assert_eq!(
+            bytes,
+            [FRAME_MAGIC, 3, FLAG_SYNC, 7, 2, 0x10, 0x20, crc[0], crc[1]]
+        );
+
+        let bytes = frame
+            .encode(FrameFormat::Checked, LengthEncoding::Varint)
+            .unwrap();
+        let header_check = crc16(&[3, FLAG_SYNC, 7, 2]) as u8;
+        let crc = crc16(&[3, FLAG_SYNC, 7, 2, header_check, 0x10, 0x20]).to_be_bytes();
+        assert_eq!(
+            bytes,
+            [
+                FRAME_MAGIC,
+                3,
+                FLAG_SYNC,
+                7,
+                2,
+                header_check,
+                0x10,
+                0x20,
+                crc[0],
+                crc[1]
+            ]
+        );
+    }
+
+    #[test]
+    fn corrupted_length_does_not_stall_the_decoder() {
+        let first = Frame::data(1, vec![0x55; 10]);
+        let second = Frame::data(2, vec![0x66; 10]);
+
+        let mut bytes = first
+            .encode(FrameFormat::Checked, LengthEncoding::Varint)
+            .unwrap();
+        // Claims 16383 bytes
+        bytes[4] = 0xFF;
+        bytes.insert(5, 0x7F);
+        bytes.extend(
+            second
+                .encode(FrameFormat::Checked, LengthEncoding::Varint)
+                .unwrap(),
+        );
+
+        let mut decoder = FrameDecoder::new(FrameFormat::Checked, LengthEncoding::Varint);
+        decoder.push(&bytes);
+
+        assert_eq!(decoder.next_frame().unwrap(), Some(second));
+        assert!(decoder.take_resyncs() > 0);
+    }
+
+    #[test]
+    fn corrupted_payload_is_skipped() {
+        let first = Frame::data(1, vec![0x55; 10]);
+        let second = Frame::data(2, vec![0x66; 10]);
+
+        for length in [LengthEncoding::Byte, LengthEncoding::Varint] {
+            let mut bytes = first.encode(FrameFormat::Checked, length).unwrap();
+            bytes[8] ^= 0x01;
+            bytes.extend(second.encode(FrameFormat::Checked, length).unwrap());
+
+            assert_eq!(
+                decode_all(FrameFormat::Checked, length, &bytes),
+                std::slice::from_ref(&second)
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let frames = [
+            Frame::data(1, vec![1, 2, 3]),
+            Frame {
+                id: 2,
+                flags: FLAG_COMPRESSED,
+                seq: 9,
+                payload: vec![0xAA; 300],
+            },
+            Frame {
+                id: 3,
+                flags: FLAG_ACK | FLAG_NEED_SYNC,
+                seq: 255,
+                payload: vec![0],
+            },
+        ];
+
+        let mut bytes = vec![];
+        for frame in &frames {
+            bytes.extend(
+                frame
+                    .encode(FrameFormat::Checked, LengthEncoding::Varint)
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            decode_all(FrameFormat::Checked, LengthEncoding::Varint, &bytes),
+            frames
+        );
+
+        // Plain frames keep nothing but the compressed flag
+        let mut bytes = vec![];
+        for frame in &frames {
+            bytes.extend(
+                frame
+                    .encode(FrameFormat::Plain, LengthEncoding::Varint)
+                    .unwrap(),
+            );
+        }
+        let plain = frames.map(|frame| Frame {
+            flags: frame.flags & FLAG_COMPRESSED,
+            seq: 0,
+            ..frame
+        });
+        assert_eq!(
+            decode_all(FrameFormat::Plain, LengthEncoding::Varint, &bytes),
+            plain
+        );
+    }
+
+    #[test]
+    fn compressed_plain_frame_is_wrapped() {
+        let frame = Frame {
+            flags: FLAG_COMPRESSED,
+            ..Frame::data(4, vec![0x10, 0x20])
+        };
+
+        let bytes = frame
+            .encode(FrameFormat::Plain, LengthEncoding::Byte)
+            .unwrap();
+        assert_eq!(bytes, [COMPRESSED_ID, 3, 4, 0x10, 0x20]);
+        assert_eq!(
+            decode_all(FrameFormat::Plain, LengthEncoding::Byte, &bytes),
+            [frame]
+        );
+    }
+
+    #[test]
+    fn zero_length_plain_frame_is_an_error() {
+        let mut decoder = FrameDecoder::new(FrameFormat::Plain, LengthEncoding::Byte);
+        decoder.push(&[5, 0]);
+
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::ZeroLength(5))
+        ));
+    }
+}
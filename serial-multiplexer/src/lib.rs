@@ -0,0 +1,39 @@
+//! Send multiple serial devices over a single serial link.
+//!
+//! A [`Multiplexer`] carries any number of [`Channel`]s over one [`Transport`]. Both the
+//! multiplexed link and the channels are transport-agnostic: real serial ports, pseudo
+//! terminals, TCP or Unix sockets and in-memory [`Pipe`]s all work the same way.
+//!
+//! [`Multiplexer::run`] serves all of them from the calling thread, and returns when a
+//! [`ShutdownHandle`] asks it to.
+
+pub mod capture;
+pub mod channel;
+pub mod compress;
+pub mod control;
+pub mod frame;
+pub mod line;
+pub mod listener;
+pub mod multiplexer;
+pub mod poll;
+pub mod reliable;
+pub mod scheduler;
+pub mod secure;
+pub mod serial_connection;
+pub mod stats;
+pub mod tee;
+pub mod transport;
+
+pub use capture::Capture;
+pub use channel::{Channel, CoalesceSettings};
+pub use control::{ControlHandle, ControlMessage, ControlSettings, LinkStatus};
+pub use frame::{Frame, FrameDecoder, FrameFormat, LengthEncoding};
+pub use line::LineState;
+pub use listener::Listener;
+pub use multiplexer::{Multiplexer, ShutdownHandle};
+pub use reliable::ReliableSettings;
+pub use scheduler::{DropPolicy, QueueSettings};
+pub use secure::PresharedKey;
+pub use stats::Stats;
+pub use tee::{LogSettings, Tee};
+pub use transport::{Pipe, Stream, Transport};
@@ -0,0 +1,112 @@
+use serialport::{DataBits, FlowControl, Parity, StopBits};
+
+/// Serial line settings and modem control lines of a channel, as set by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineState {
+    /// 0 means the client hung up the line
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+    pub dtr: bool,
+    pub rts: bool,
+}
+
+pub const ENCODED_LEN: usize = 9;
+
+const LINE_DTR: u8 = 1;
+const LINE_RTS: u8 = 2;
+
+impl LineState {
+    pub fn encode(&self) -> [u8; ENCODED_LEN] {
+        let baud_rate = self.baud_rate.to_be_bytes();
+
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+        };
+
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+
+        let flow_control = match self.flow_control {
+            FlowControl::None => 0,
+            FlowControl::Software => 1,
+            FlowControl::Hardware => 2,
+        };
+
+        let mut lines = 0;
+        if self.dtr {
+            lines |= LINE_DTR;
+        }
+        if self.rts {
+            lines |= LINE_RTS;
+        }
+
+        [
+            baud_rate[0],
+            baud_rate[1],
+            baud_rate[2],
+            baud_rate[3],
+            data_bits,
+            parity,
+            stop_bits,
+            flow_control,
+            lines,
+        ]
+    }
+
+    /// Returns `None` if the bytes are too short or hold an unknown setting.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENCODED_LEN] = bytes.get(..ENCODED_LEN)?.try_into().ok()?;
+
+        let data_bits = match bytes[4] {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            8 => DataBits::Eight,
+            _ => return None,
+        };
+
+        let parity = match bytes[5] {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            _ => return None,
+        };
+
+        let stop_bits = match bytes[6] {
+            1 => StopBits::One,
+            2 => StopBits::Two,
+            _ => return None,
+        };
+
+        let flow_control = match bytes[7] {
+            0 => FlowControl::None,
+            1 => FlowControl::Software,
+            2 => FlowControl::Hardware,
+            _ => return None,
+        };
+
+        Some(LineState {
+            baud_rate: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            data_bits,
+            parity,
+            stop_bits,
+            flow_control,
+            dtr: bytes[8] & LINE_DTR != 0,
+            rts: bytes[8] & LINE_RTS != 0,
+        })
+    }
+}
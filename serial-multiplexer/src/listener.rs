@@ -0,0 +1,163 @@
+use std::fs::{create_dir_all, remove_file};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::transport::Transport;
+
+// How long the listener is left alone after accepting failed, e.g. when out of descriptors.
+// The waiting client keeps it readable, so retrying right away would spin.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A listening socket that accepts connections from a client.
+pub trait Accept: AsRawFd + Send {
+    type Stream: Read + Write + AsRawFd + Send;
+
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+
+    fn shutdown_stream(stream: &Self::Stream);
+
+    fn set_nonblocking(&self) -> io::Result<()>;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        let (stream, address) = self.accept()?;
+        stream.set_nonblocking(true)?;
+        // Channels carry small interactive messages, don't let Nagle hold them back
+        stream.set_nodelay(true)?;
+        println!("Accepted TCP client {}", address);
+        Ok(stream)
+    }
+
+    fn shutdown_stream(stream: &TcpStream) {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, true)
+    }
+}
+
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        let (stream, _) = self.accept()?;
+        stream.set_nonblocking(true)?;
+        println!("Accepted Unix socket client");
+        Ok(stream)
+    }
+
+    fn shutdown_stream(stream: &UnixStream) {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, true)
+    }
+}
+
+/// Exposes a channel as a listening socket. One client is served at a time.
+///
+/// Nothing is read or written until a client connects. When the client goes away the listener
+/// waits for a new one, so the channel survives reconnects. Data for the channel waits meanwhile.
+pub struct Listener<L: Accept> {
+    listener: L,
+    connection: Option<L::Stream>,
+    // Accepting the last client failed
+    accept_failed: bool,
+}
+
+impl<L: Accept> Listener<L> {
+    pub fn new(listener: L) -> io::Result<Self> {
+        listener.set_nonblocking()?;
+
+        Ok(Listener {
+            listener,
+            connection: None,
+            accept_failed: false,
+        })
+    }
+
+    /// The listening socket, e.g. to serve something else than a channel on it.
+    pub fn into_inner(self) -> L {
+        self.listener
+    }
+}
+
+impl Listener<TcpListener> {
+    pub fn bind_tcp(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Listener::new(TcpListener::bind(address)?)
+    }
+}
+
+impl Listener<UnixListener> {
+    /// Binds a Unix socket at `path`, replacing a stale socket left behind by a previous run.
+    pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let _ = remove_file(path);
+        Listener::new(UnixListener::bind(path)?)
+    }
+}
+
+impl<L: Accept> Transport for Listener<L> {
+    // The client's socket, or the listening socket while waiting for one
+    fn fd(&self) -> Option<RawFd> {
+        match &self.connection {
+            Some(connection) => Some(connection.as_raw_fd()),
+            None => Some(self.listener.as_raw_fd()),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    // Without a client, the listening socket being readable means one is waiting to be accepted
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.connection {
+            Some(connection) => connection.read(buf),
+            None => match self.listener.accept_stream() {
+                Ok(connection) => {
+                    self.connection = Some(connection);
+                    Err(io::ErrorKind::WouldBlock.into())
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => Err(e),
+                Err(e) => {
+                    self.accept_failed = true;
+                    Err(e)
+                }
+            },
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.connection {
+            Some(connection) => connection.write(buf),
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn reopen(&mut self) -> io::Result<Option<Duration>> {
+        if let Some(connection) = self.connection.take() {
+            L::shutdown_stream(&connection);
+        }
+
+        if std::mem::take(&mut self.accept_failed) {
+            return Ok(Some(ACCEPT_RETRY_INTERVAL));
+        }
+
+        Ok(None)
+    }
+}
@@ -1,281 +1,498 @@
 use clap::Parser;
-use serialport::{Error, SerialPort, TTYPort};
+use serialport::TTYPort;
 use std::{
-    collections::HashMap,
-    fs::{self, create_dir, remove_file},
-    io::{Read, Write},
-    os::unix::fs::symlink,
-    path::PathBuf,
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    net::TcpListener,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex, mpsc::Receiver},
-    time::Duration,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
-use crate::config::{Args, SerialEntryRaw};
-use crate::serial_connection::*;
+use serial_multiplexer::capture::{Direction, channel_data, read_capture};
+use serial_multiplexer::serial_connection::{
+    DeviceSelector, SerialConnectionSettings, SerialPortManager,
+};
+use serial_multiplexer::stats::{serve_prometheus, serve_text};
+use serial_multiplexer::{
+    Capture, Channel, ControlSettings, LengthEncoding, Listener, Multiplexer, PresharedKey,
+    ReliableSettings, Tee,
+};
+
+use crate::config::{Args, ChannelConfig, Command, Endpoint, Role, load_config};
+use crate::pty::{PtySettings, create_pty, remove_links, remove_links_on_signal, resolve_group};
+use crate::signal::on_signal;
 mod config;
-mod serial_connection;
+mod pty;
+mod signal;
 
 fn main() {
     println!("Hello, world!");
     let args = Args::parse();
-    if (!args.with_virtual_ports && !args.with_real_ports)
-        || (args.with_virtual_ports && args.with_real_ports)
-    {
-        eprintln!("You must specify either --with_virtual_ports or --with_real_ports");
-        exit(1);
+
+    if let Some(Command::Stats { socket }) = &args.command {
+        print_stats(socket);
+        return;
     }
 
-    let config_path = PathBuf::from(&args.config);
-    if !config_path.exists() {
-        eprintln!("Config file does not exist: {}", config_path.display());
-        exit(2);
+    if let Some(Command::Replay {
+        capture,
+        channel,
+        sent,
+        link,
+        now,
+        fast,
+    }) = &args.command
+    {
+        let direction = if *sent {
+            Direction::Sent
+        } else {
+            Direction::Received
+        };
+        let permissions = (args.pty_mode, pty_group(&args));
+        replay(capture, *channel, direction, link, permissions, *now, *fast);
+        return;
     }
 
-    let config = fs::read_to_string(&config_path).unwrap();
-    let serial_ports_raw: HashMap<String, SerialEntryRaw> = toml::from_str(&config).unwrap();
-    if serial_ports_raw.is_empty() {
-        eprintln!("No serial ports found in the config file.");
-        exit(3);
+    if let Some(Command::CheckConfig { config }) = &args.command {
+        let channels = read_config(config, &args);
+        println!("Config is valid, {} serial ports:", channels.len());
+        for channel in channels {
+            println!(
+                "  [{}] id {}: {}",
+                channel.name,
+                channel.id,
+                describe(&channel.endpoint)
+            );
+
+            if !channel.coalesce.max_latency.is_zero() {
+                println!(
+                    "    coalescing up to {} bytes for {}ms",
+                    channel.coalesce.max_bytes.unwrap_or(args.max_payload_len()),
+                    channel.coalesce.max_latency.as_millis()
+                );
+            }
+
+            println!(
+                "    priority {}, weight {}, queue of {} blocks, {:?} when full",
+                channel.queue.priority,
+                channel.queue.weight,
+                channel.queue.capacity,
+                channel.queue.policy
+            );
+
+            if channel.compress {
+                println!("    compressed if the peer agrees");
+            }
+
+            if let Some(log) = &channel.log {
+                println!(
+                    "    logged to {}, rotated at {} bytes, keeping {}",
+                    log.path.display(),
+                    log.max_bytes,
+                    log.keep
+                );
+            }
+
+            if let Some(mirror) = &channel.mirror {
+                println!("    mirrored at {}", mirror.link_path.display());
+            }
+        }
+        return;
     }
 
+    let device = args.device.clone().expect("Device is required by clap");
+    let channels = match &args.config {
+        Some(config) => read_config(config, &args),
+        None if args.role == Some(Role::Host) => vec![],
+        None => {
+            eprintln!("A config file is required unless running with --role host");
+            exit(2);
+        }
+    };
+
     let multiplexed_port_manager = SerialPortManager::with_settings(SerialConnectionSettings {
         baud_rate: args.baud,
-        device_path: args.device,
+        device: DeviceSelector::Path(device),
     });
 
-    let mut unused = vec![];
-    let (main_bus_sender, main_bus_receiver) = std::sync::mpsc::channel::<DataBlock>();
+    let mut multiplexer = Multiplexer::new(multiplexed_port_manager);
 
-    let mut sender_processors = vec![];
-    let mut receiver_processors = vec![];
-    let mut senders = vec![];
+    if args.reliable {
+        multiplexer = multiplexer.with_reliable(ReliableSettings {
+            window_size: args.window_size,
+            retransmit_timeout: Duration::from_millis(args.retransmit_timeout_ms),
+        });
+    }
 
-    if args.with_real_ports {
-        serial_ports_raw.iter().for_each(|f| {
-            let config = SerialConnectionSettings {
-                baud_rate: f.1.baud_rate,
-                device_path: f.1.device_path.clone(),
-            };
+    if args.large_frames {
+        multiplexer = multiplexer.with_length_encoding(LengthEncoding::Varint);
+    }
 
-            let (port_sender, port_receiver) = std::sync::mpsc::channel::<DataBlock>();
+    if let Some(path) = &args.psk_file {
+        multiplexer = multiplexer.with_psk(read_psk(path));
+    }
 
-            let serial_port_manager = SerialPortManager::with_settings(config);
-            let serial_port_manager_ref = Arc::new(Mutex::new(serial_port_manager));
+    if args.control {
+        multiplexer = multiplexer.with_control(ControlSettings {
+            keepalive_interval: Duration::from_millis(args.keepalive_interval_ms),
+            announce_channels: args.role == Some(Role::Printer),
+        });
+    }
+
+    let shutdown = multiplexer.shutdown_handle();
+    let mut signaled = false;
+
+    on_signal(move |signal| {
+        // Don't wait on a stuck loop when asked twice
+        if signaled {
+            remove_links();
+            exit(128 + signal);
+        }
 
-            sender_processors.push(SerialConnectionSenderProcessor {
-                id: f.1.id,
-                port_manager: serial_port_manager_ref.clone(),
-                port_receiver: port_receiver,
-            });
+        println!("Received signal {}, shutting down", signal);
+        signaled = true;
+        shutdown.shutdown();
+    });
 
-            receiver_processors.push(SerialConnectionReceiverProcessor {
-                id: f.1.id,
-                port_manager: serial_port_manager_ref,
-                write_to_main_bus: main_bus_sender.clone(),
-            });
+    if args.role == Some(Role::Host) {
+        let defaults = args.defaults();
+        let gid = pty_group(&args);
+        // Keeps the slaves open, like `unused` below
+        let slaves = Mutex::new(vec![]);
+
+        multiplexer = multiplexer.with_channel_factory(move |id, name| {
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' cannot be used as a file name", name),
+                ));
+            }
 
-            senders.push(SerialConnectionSender {
-                id: f.1.id,
-                port_sender: port_sender,
-            });
+            let pty = PtySettings {
+                link_path: defaults.link_dir.join(name),
+                mode: defaults.pty_mode,
+                gid,
+            };
+            let (master, slave) = create_pty(&pty)?;
+            slaves.lock().unwrap().push(slave);
+            println!("Linked {} to {}", name, pty.link_path.display());
+
+            // Compression is up to the printer, it is only used if its entry asks for it
+            Ok(Channel::new(id, SerialPortManager::with_port(master)?)
+                .with_name(name)
+                .with_compression(true))
         });
-    } else {
-        serial_ports_raw.iter().for_each(|f| {
-            let entry = f.1;
+    }
 
-            let (port_sender, port_receiver) = std::sync::mpsc::channel::<DataBlock>();
+    if let Some(path) = &args.capture {
+        let capture = Capture::create(path).unwrap_or_else(|e| {
+            eprintln!("Failed to create capture {}: {}", path.display(), e);
+            exit(4);
+        });
+        multiplexer = multiplexer.with_capture(capture);
+    }
 
-            let (mut master, slave) = TTYPort::pair().expect("Unable to create ptty pair");
-            master.set_timeout(Duration::MAX).unwrap();
+    let control = multiplexer.control_handle();
+    let stats = multiplexer.stats();
+    let mut unused = vec![];
 
-            let name = slave.name().unwrap();
-            unused.push(slave);
+    for channel in channels {
+        let tee = (channel.log.is_some() || channel.mirror.is_some())
+            .then(|| create_tee(&channel, &mut unused));
 
-            let mut link_path = std::env::temp_dir();
-            link_path.push("vtty");
-            if !link_path.exists() {
-                create_dir(&link_path).unwrap();
+        let mut transport = match channel.endpoint {
+            Endpoint::Serial { device, baud_rate } => {
+                let config = SerialConnectionSettings { baud_rate, device };
+
+                if let Some(control) = &control {
+                    control.notify_baud_rate(channel.id, baud_rate);
+                }
+
+                Channel::new(channel.id, SerialPortManager::with_settings(config))
+            }
+            Endpoint::Pty(pty) => {
+                let (master, slave) = create_pty(&pty).unwrap_or_else(|e| {
+                    eprintln!(
+                        "Failed to create {} for {}: {}",
+                        pty.link_path.display(),
+                        channel.name,
+                        e
+                    );
+                    remove_links();
+                    exit(4);
+                });
+                unused.push(slave);
+
+                let port = SerialPortManager::with_port(master).unwrap_or_else(|e| {
+                    eprintln!("Failed to set up {}: {}", channel.name, e);
+                    remove_links();
+                    exit(4);
+                });
+
+                Channel::new(channel.id, port)
             }
+            Endpoint::Tcp { listen } => {
+                let listener = Listener::bind_tcp(&listen).unwrap_or_else(|e| {
+                    eprintln!("Failed to listen on {} for {}: {}", listen, channel.name, e);
+                    exit(4);
+                });
 
-            link_path.push(f.0);
-            let _ = remove_file(&link_path);
+                Channel::new(channel.id, listener)
+            }
+            Endpoint::Unix { path } => {
+                let listener = Listener::bind_unix(&path).unwrap_or_else(|e| {
+                    eprintln!(
+                        "Failed to listen on {} for {}: {}",
+                        path.display(),
+                        channel.name,
+                        e
+                    );
+                    exit(4);
+                });
+
+                Channel::new(channel.id, listener)
+            }
+        };
 
-            symlink(name, link_path).unwrap();
+        if let Some(tee) = tee {
+            transport = transport.with_tee(tee);
+        }
 
-            let serial_port_manager = SerialPortManager::with_port(master);
-            let serial_port_manager_ref = Arc::new(Mutex::new(serial_port_manager));
+        multiplexer.add_channel(
+            transport
+                .with_name(&channel.name)
+                .with_coalesce(channel.coalesce)
+                .with_queue(channel.queue)
+                .with_compression(channel.compress),
+        );
+    }
 
-            sender_processors.push(SerialConnectionSenderProcessor {
-                id: entry.id,
-                port_manager: serial_port_manager_ref.clone(),
-                port_receiver: port_receiver,
-            });
+    if let Some(path) = &args.stats_socket {
+        let listener = Listener::bind_unix(path).unwrap_or_else(|e| {
+            eprintln!("Failed to listen on {} for stats: {}", path.display(), e);
+            exit(4);
+        });
+        let stats = stats.clone();
 
-            receiver_processors.push(SerialConnectionReceiverProcessor {
-                id: entry.id,
-                port_manager: serial_port_manager_ref,
-                write_to_main_bus: main_bus_sender.clone(),
-            });
+        std::thread::spawn(move || {
+            if let Err(e) = serve_text(listener.into_inner(), stats) {
+                eprintln!("Stats socket stopped: {}", e);
+            }
+        });
+    }
 
-            senders.push(SerialConnectionSender {
-                id: entry.id,
-                port_sender,
-            });
+    if let Some(address) = &args.prometheus {
+        let listener = TcpListener::bind(address).unwrap_or_else(|e| {
+            eprintln!("Failed to listen on {} for metrics: {}", address, e);
+            exit(4);
         });
+        let stats = stats.clone();
+
+        std::thread::spawn(move || serve_prometheus(listener, stats));
     }
 
     println!("Starting communication loop...");
-    communicate(
-        sender_processors,
-        receiver_processors,
-        senders,
-        main_bus_receiver,
-        multiplexed_port_manager,
-    );
+    let result = multiplexer.run();
+    remove_links();
+
+    if let Err(e) = result {
+        eprintln!("Multiplexed port failed: {}", e);
+        exit(5);
+    }
 }
 
-fn communicate(
-    sender_processors: Vec<SerialConnectionSenderProcessor>,
-    receiver_processors: Vec<SerialConnectionReceiverProcessor>,
-    senders: Vec<SerialConnectionSender>,
-    main_bus_receiver: Receiver<DataBlock>,
-    multiplexed_port_manager: SerialPortManager,
-) {
-    let multiplexed_port_manager_ref = Arc::new(Mutex::new(multiplexed_port_manager));
-    let multiplexed_port_manager_ref_clone = multiplexed_port_manager_ref.clone();
+fn read_config(path: &str, args: &Args) -> Vec<ChannelConfig> {
+    let config_path = PathBuf::from(path);
+    if !config_path.exists() {
+        eprintln!("Config file does not exist: {}", config_path.display());
+        exit(2);
+    }
 
-    sender_processors.into_iter().for_each(|f| {
-        std::thread::spawn(move || {
-            f.process_loop();
-        });
-    });
+    let config = match fs::read_to_string(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", config_path.display(), e);
+            exit(2);
+        }
+    };
 
-    receiver_processors.into_iter().for_each(|f| {
-        std::thread::spawn(move || {
-            f.process_loop();
+    match load_config(&config, &args.defaults()) {
+        Ok(channels) => channels,
+        Err(problems) => {
+            for problem in problems {
+                eprintln!("{}: {}", config_path.display(), problem);
+            }
+            exit(3);
+        }
+    }
+}
+
+fn replay(
+    path: &Path,
+    id: u8,
+    direction: Direction,
+    link_path: &Path,
+    (mode, gid): (Option<u32>, Option<u32>),
+    now: bool,
+    fast: bool,
+) {
+    let frames = File::open(path)
+        .and_then(|file| read_capture(BufReader::new(file)))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read capture {}: {}", path.display(), e);
+            exit(2);
         });
+
+    let data = channel_data(&frames, id, direction);
+    if data.is_empty() {
+        eprintln!("Capture has no data for channel {} in this direction", id);
+        exit(2);
+    }
+
+    remove_links_on_signal();
+
+    let pty = PtySettings {
+        link_path: link_path.to_path_buf(),
+        mode,
+        gid,
+    };
+    let (mut master, _slave) = create_pty(&pty).unwrap_or_else(|e| {
+        eprintln!("Failed to create {}: {}", link_path.display(), e);
+        exit(4);
     });
+    let mut client = master.try_clone_native().expect("Failed to clone pty");
+
+    if !now {
+        println!(
+            "Waiting for a client to write to {}...",
+            link_path.display()
+        );
+        let mut buff = [0u8; 1];
+        if let Err(e) = client.read(&mut buff) {
+            eprintln!("Failed to read from {}: {}", link_path.display(), e);
+            remove_links();
+            exit(5);
+        }
+    }
 
+    // Whatever the client sends is ignored, but it must not fill up the pty
     std::thread::spawn(move || {
-        multiplexed_port_sender(multiplexed_port_manager_ref_clone, main_bus_receiver);
+        let mut buff = [0u8; 256];
+        while client.read(&mut buff).is_ok() {}
     });
 
-    let serial_ports = senders
-        .into_iter()
-        .map(|f| (f.id as u32, f))
-        .collect::<HashMap<u32, SerialConnectionSender>>();
+    println!("Replaying {} blocks to {}", data.len(), link_path.display());
+    let first = data[0].0;
+    let start = Instant::now();
 
-    multiplexed_port_receiver(serial_ports, multiplexed_port_manager_ref);
-}
+    for (timestamp, payload) in data {
+        if !fast {
+            let due = start + timestamp.saturating_sub(first);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
 
-fn multiplexed_port_sender(
-    multiplexed_port_manager: Arc<Mutex<SerialPortManager>>,
-    main_bus_receiver: Receiver<DataBlock>,
-) {
-    let mut multiplexed_port = give_port(&multiplexed_port_manager);
+        if let Err(e) = master.write_all(&payload) {
+            eprintln!("Failed to write to {}: {}", link_path.display(), e);
+            remove_links();
+            exit(5);
+        }
+    }
 
+    println!("Replay finished, press Ctrl-C to exit");
     loop {
-        let data = main_bus_receiver.recv().unwrap();
-
-        let len = data.data.len();
-        let mut buff = [0u8; 2 + 255];
-        buff[0] = data.id as u8;
-        buff[1] = len as u8;
-        buff[2..(2 + len)].copy_from_slice(&data.data);
-
-        if let Err(e) = multiplexed_port.write_all(&buff[..(2 + len)]) {
-            // Something horrible happened, the multiplexed port is likely dead. Dropping packets until port is alive again...
-            eprintln!("Failed to write to multiplexed port: {}", e);
-            multiplexed_port = give_port(&multiplexed_port_manager);
+        std::thread::park();
+    }
+}
 
-            while main_bus_receiver.try_recv().is_ok() {
-                // Clear the main bus receiver
-            }
+// Group for virtual ports created outside the config, i.e. for the peer's channels and replays
+fn pty_group(args: &Args) -> Option<u32> {
+    let group = args.pty_group.as_ref()?;
 
-            continue;
+    match resolve_group(group) {
+        Some(gid) => Some(gid),
+        None => {
+            eprintln!("Group {} does not exist", group);
+            exit(2);
         }
-
-        #[cfg(debug_assertions)]
-        println!("Sent {} bytes for device {}", data.data.len(), data.id);
     }
 }
 
-fn multiplexed_port_receiver(
-    serial_connection_senders: HashMap<u32, SerialConnectionSender>,
-    multiplexed_port_manager: Arc<Mutex<SerialPortManager>>,
-) {
-    let mut multiplexed_port = give_port(&multiplexed_port_manager);
-    let mut senders = serial_connection_senders;
-
-    loop {
-        let mut mini_buff = [0u8; 2];
+fn create_tee(channel: &ChannelConfig, unused: &mut Vec<TTYPort>) -> Tee {
+    let mut tee = Tee::new();
 
-        if let Err(e) = multiplexed_port.read_exact(&mut mini_buff) {
+    if let Some(log) = &channel.log {
+        tee = tee.with_log(log.clone()).unwrap_or_else(|e| {
             eprintln!(
-                "Failed to read from multiplexed port (reading header): {}",
+                "Failed to open log {} for {}: {}",
+                log.path.display(),
+                channel.name,
                 e
             );
-            multiplexed_port = give_port(&multiplexed_port_manager);
-            continue;
-        }
+            remove_links();
+            exit(4);
+        });
+    }
 
-        let id = mini_buff[0];
-        let length = mini_buff[1] as usize;
+    if let Some(mirror) = &channel.mirror {
+        let port = create_pty(mirror).and_then(|(master, slave)| {
+            unused.push(slave);
+            SerialPortManager::with_port(master)
+        });
 
-        if length == 0 {
-            let reason = format!("Received zero-length data for device {}", id);
-            clear_buff_with_error_handling(
-                &mut multiplexed_port,
-                &reason,
-                &multiplexed_port_manager,
+        let port = port.unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to create mirror {} for {}: {}",
+                mirror.link_path.display(),
+                channel.name,
+                e
             );
-            continue;
-        }
+            remove_links();
+            exit(4);
+        });
 
-        let mut buff = vec![0u8; length];
+        tee = tee.with_mirror(port);
+    }
 
-        if let Err(e) = multiplexed_port.read_exact(&mut buff) {
-            eprintln!("Failed to read from multiplexed port (reading data): {}", e);
-            multiplexed_port = give_port(&multiplexed_port_manager);
-            continue;
-        }
+    tee
+}
 
-        #[cfg(debug_assertions)]
-        println!("Received {} bytes for device {}", length, id);
+fn read_psk(path: &Path) -> PresharedKey {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read key {}: {}", path.display(), e);
+        exit(2);
+    });
 
-        if let Some(port) = senders.get_mut(&(id as u32)) {
-            port.port_sender
-                .send(DataBlock { id, data: buff })
-                .expect("Failed to send data block to port sender");
-        } else {
-            let reason = format!("Device with id {} does not exist", id);
-            clear_buff_with_error_handling(
-                &mut multiplexed_port,
-                &reason,
-                &multiplexed_port_manager,
-            );
-        }
-    }
+    PresharedKey::from_hex(&text).unwrap_or_else(|e| {
+        eprintln!("Invalid key in {}: {}", path.display(), e);
+        exit(2);
+    })
 }
 
-fn clear_buffer(port: &TTYPort, reason: &str) -> Result<(), Error> {
-    eprintln!(
-        "{}. Assuming we're not in sync! Waiting 1s and trying again...",
-        reason
-    );
-    port.clear(serialport::ClearBuffer::Input)?;
-    std::thread::sleep(Duration::from_secs(1u64));
-    port.clear(serialport::ClearBuffer::Input)
+fn print_stats(socket: &PathBuf) {
+    let mut stream = UnixStream::connect(socket).unwrap_or_else(|e| {
+        eprintln!("Failed to connect to {}: {}", socket.display(), e);
+        exit(2);
+    });
+
+    let mut stats = String::new();
+    if let Err(e) = stream.read_to_string(&mut stats) {
+        eprintln!("Failed to read stats: {}", e);
+        exit(2);
+    }
+
+    print!("{}", stats);
 }
 
-fn clear_buff_with_error_handling(
-    port: &mut TTYPort,
-    reason: &str,
-    port_manager: &Arc<Mutex<SerialPortManager>>,
-) {
-    if let Err(e) = clear_buffer(port, &reason) {
-        eprintln!("Failed to clear buffer: {}", e);
-        *port = give_port(port_manager);
+fn describe(endpoint: &Endpoint) -> String {
+    match endpoint {
+        Endpoint::Serial { device, baud_rate } => {
+            format!("serial {} at {} baud", device, baud_rate)
+        }
+        Endpoint::Pty(pty) => format!("pty linked at {}", pty.link_path.display()),
+        Endpoint::Tcp { listen } => format!("tcp listening on {}", listen),
+        Endpoint::Unix { path } => format!("unix socket at {}", path.display()),
     }
 }
@@ -0,0 +1,833 @@
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::capture::{Capture, Direction};
+use crate::channel::{Channel, ChannelState, DataBlock, FIRST_RESERVED_ID};
+use crate::compress::decompress;
+use crate::control::{
+    CONTROL_CHANNEL_ID, ControlHandle, ControlProcessor, ControlSettings, LinkStatus,
+};
+use crate::frame::{FLAG_COMPRESSED, Frame, FrameDecoder, FrameFormat, LengthEncoding};
+use crate::poll::{PollSet, WakeReceiver, Waker, waker};
+use crate::reliable::{Destination, ReliableLink, ReliableSettings};
+use crate::scheduler::{MainBus, QueueSettings};
+use crate::secure::{HANDSHAKE_ID, PresharedKey, SEAL_OVERHEAD, SecureLink};
+use crate::stats::Stats;
+use crate::transport::{OUTPUT_LOW_WATER, Transport};
+
+// How long input is thrown away after losing sync on the plain frame format
+const RESYNC_WAIT: Duration = Duration::from_secs(1);
+
+// How often the link's output buffer is checked while it is above the low water mark
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+// Read buffer size for the multiplexed link. Frames may span several reads.
+const LINK_READ_LEN: usize = 4096;
+
+/// Carries any number of [`Channel`]s over a single transport.
+///
+/// Both ends of the link must use the same channel ids, the same reliable setting, the same
+/// length encoding, the same pre-shared key if any, and either both enable or both disable the
+/// control channel.
+pub struct Multiplexer<T: Transport + 'static> {
+    transport: T,
+    channels: Vec<Channel>,
+    reliable: Option<ReliableSettings>,
+    length: LengthEncoding,
+    control: Option<ControlChannel>,
+    status: Arc<Mutex<LinkStatus>>,
+    stats: Arc<Stats>,
+    capture: Option<Capture>,
+    factory: Option<ChannelFactory>,
+    psk: Option<PresharedKey>,
+    shutdown: Arc<AtomicBool>,
+    waker: Waker,
+    wake_receiver: WakeReceiver,
+}
+
+struct ControlChannel {
+    settings: ControlSettings,
+    sender: Sender<DataBlock>,
+    receiver: Receiver<DataBlock>,
+}
+
+/// Stops a running [`Multiplexer`] from another thread, e.g. when a signal arrives.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    waker: Waker,
+}
+
+impl ShutdownHandle {
+    /// Makes [`Multiplexer::run`] return. A multiplexer that is not running yet returns right away.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.waker.wake();
+    }
+}
+
+impl<T: Transport + 'static> Multiplexer<T> {
+    pub fn new(transport: T) -> Self {
+        let (waker, wake_receiver) = waker().expect("Failed to create event loop waker");
+
+        Multiplexer {
+            transport,
+            channels: vec![],
+            reliable: None,
+            length: LengthEncoding::Byte,
+            control: None,
+            status: Arc::new(Mutex::new(LinkStatus::default())),
+            stats: Arc::new(Stats::default()),
+            capture: None,
+            factory: None,
+            psk: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            waker,
+            wake_receiver,
+        }
+    }
+
+    /// Acknowledge and retransmit frames per channel.
+    pub fn with_reliable(mut self, settings: ReliableSettings) -> Self {
+        self.reliable = Some(settings);
+        self
+    }
+
+    /// How frame lengths are sent. [`LengthEncoding::Varint`] allows frames above 255 bytes.
+    pub fn with_length_encoding(mut self, length: LengthEncoding) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// Exchange keepalives and channel state with the peer over the control channel.
+    pub fn with_control(mut self, settings: ControlSettings) -> Self {
+        let (sender, receiver) = std::sync::mpsc::channel::<DataBlock>();
+        self.control = Some(ControlChannel {
+            settings,
+            sender,
+            receiver,
+        });
+        self
+    }
+
+    /// Encrypt and authenticate every frame with a key the peer must have as well.
+    ///
+    /// Nothing but the handshake is sent or accepted until the peer proved it has the key.
+    pub fn with_psk(mut self, key: PresharedKey) -> Self {
+        self.psk = Some(key);
+        self
+    }
+
+    /// Record every frame sent or received on the link.
+    pub fn with_capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Create local channels for the channels the peer announces. Needs the control channel.
+    pub fn with_channel_factory(
+        mut self,
+        factory: impl Fn(u8, &str) -> io::Result<Channel> + Send + 'static,
+    ) -> Self {
+        self.factory = Some(Box::new(factory));
+        self
+    }
+
+    /// Handle for sending notifications to the peer, if the control channel is enabled.
+    pub fn control_handle(&self) -> Option<ControlHandle> {
+        self.control
+            .as_ref()
+            .map(|control| ControlHandle::new(control.sender.clone(), self.waker.clone()))
+    }
+
+    /// Handle for stopping the multiplexer while it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            requested: self.shutdown.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// What the control channel knows about the peer. Stays at its default without it.
+    pub fn link_status(&self) -> Arc<Mutex<LinkStatus>> {
+        self.status.clone()
+    }
+
+    /// Counters for the link and every channel, updated while the multiplexer runs.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    pub fn add_channel(&mut self, channel: Channel) {
+        assert!(
+            channel.id < FIRST_RESERVED_ID,
+            "Channel id {} is reserved",
+            channel.id
+        );
+        self.channels.push(channel);
+    }
+
+    /// Runs the multiplexer and all its channels on the current thread.
+    ///
+    /// Returns once a [`ShutdownHandle`] asks it to, or with an error when the multiplexed
+    /// transport fails and cannot be reopened.
+    pub fn run(self) -> io::Result<()> {
+        let format = match self.reliable {
+            Some(_) => FrameFormat::Checked,
+            None => FrameFormat::Plain,
+        };
+
+        let mut event_loop = EventLoop {
+            link: Link {
+                transport: self.transport,
+                decoder: FrameDecoder::new(format, self.length),
+                output: vec![],
+                retry_at: None,
+                resync_at: None,
+                drain_check_at: None,
+            },
+            channels: vec![],
+            bus: MainBus::new(self.stats.clone()),
+            reliable: self
+                .reliable
+                .map(|settings| ReliableLink::new(settings, self.stats.clone())),
+            secure: self.psk.map(SecureLink::new),
+            control: None,
+            wake_receiver: self.wake_receiver,
+            shutdown: self.shutdown,
+            length: self.length,
+            stats: self.stats,
+            capture: self.capture,
+            poll_set: PollSet::default(),
+        };
+
+        if let Some(control) = self.control {
+            event_loop.stats.set_name(CONTROL_CHANNEL_ID, "control");
+
+            // Link status should not wait behind channel data
+            event_loop.bus.set_queue(
+                CONTROL_CHANNEL_ID,
+                QueueSettings {
+                    priority: u8::MAX,
+                    ..QueueSettings::default()
+                },
+            );
+
+            event_loop.control = Some(ControlProcessor::new(
+                control.settings,
+                self.status,
+                control.receiver,
+                self.factory,
+                event_loop.max_payload_len(),
+            ));
+        }
+
+        let now = Instant::now();
+
+        // Serial ports are opened on first use
+        if event_loop.link.transport.fd().is_none() {
+            event_loop.reopen_link(now)?;
+        }
+
+        for channel in self.channels {
+            event_loop.add_channel(channel, now);
+        }
+
+        event_loop.run()
+    }
+}
+
+/// Creates a local channel for a channel the peer announced in its channel table.
+///
+/// Called with the id and name of every announced channel that does not exist locally.
+pub type ChannelFactory = Box<dyn Fn(u8, &str) -> io::Result<Channel> + Send>;
+
+// The multiplexed link
+struct Link<T: Transport> {
+    transport: T,
+    decoder: FrameDecoder,
+    // Encoded frames the transport did not take yet
+    output: Vec<u8>,
+    // The transport failed and is reopened then
+    retry_at: Option<Instant>,
+    // Plain format only: sync was lost, input is thrown away until then
+    resync_at: Option<Instant>,
+    // The transport's own output buffer was too full to take another frame, check again then
+    drain_check_at: Option<Instant>,
+}
+
+impl<T: Transport> Link<T> {
+    fn is_up(&self) -> bool {
+        self.retry_at.is_none() && self.transport.fd().is_some()
+    }
+
+    // Whether the next frames may be taken off the main bus
+    fn takes_output(&self) -> bool {
+        self.is_up() && self.output.is_empty() && self.drain_check_at.is_none()
+    }
+}
+
+// Moves data between the channels and the multiplexed link, waiting on all of their
+// descriptors at once
+struct EventLoop<T: Transport> {
+    link: Link<T>,
+    channels: Vec<ChannelState>,
+    bus: MainBus,
+    reliable: Option<ReliableLink>,
+    secure: Option<SecureLink>,
+    control: Option<ControlProcessor>,
+    wake_receiver: WakeReceiver,
+    shutdown: Arc<AtomicBool>,
+    length: LengthEncoding,
+    stats: Arc<Stats>,
+    capture: Option<Capture>,
+    poll_set: PollSet,
+}
+
+impl<T: Transport> EventLoop<T> {
+    fn run(&mut self) -> io::Result<()> {
+        let mut channel_slots = vec![];
+
+        #[cfg(debug_assertions)]
+        println!("Starting event loop for {} channels", self.channels.len());
+
+        loop {
+            if self.shutdown.load(Ordering::SeqCst) {
+                println!("Shutting down");
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            self.run_timers(now)?;
+            self.report_open_channels();
+            self.write_channels(now);
+            self.write_link(now)?;
+
+            self.poll_set.clear();
+            let wake_slot = self.poll_set.add(self.wake_receiver.fd(), true, false);
+
+            let link_slot = match self.link.transport.fd() {
+                Some(fd) if self.link.is_up() => Some(self.poll_set.add(
+                    fd,
+                    self.link.resync_at.is_none(),
+                    !self.link.output.is_empty(),
+                )),
+                _ => None,
+            };
+
+            channel_slots.clear();
+            for channel in &self.channels {
+                channel_slots.push(channel.fd().map(|fd| {
+                    self.poll_set
+                        .add(fd, channel.wants_read(&self.bus), channel.wants_write())
+                }));
+            }
+
+            let timeout = self
+                .next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            self.poll_set.wait(timeout)?;
+
+            let now = Instant::now();
+
+            if self.poll_set.readable(wake_slot) {
+                self.wake_receiver.drain();
+
+                if let Some(control) = &mut self.control {
+                    control.on_notifications(&mut self.bus);
+                }
+            }
+
+            if let Some(slot) = link_slot
+                && self.poll_set.readable(slot)
+            {
+                self.read_link(now)?;
+            }
+
+            // Channels the peer announced in the meantime are not in the poll set yet
+            for (index, slot) in channel_slots.iter().enumerate() {
+                if let Some(slot) = *slot
+                    && self.poll_set.readable(slot)
+                    && let Err(e) = self.channels[index].read(&mut self.bus, now)
+                {
+                    eprintln!(
+                        "Error reading from channel {}: {}. Attempting to reconnect...",
+                        self.channels[index].id(),
+                        e
+                    );
+                    self.channel_failed(index, now);
+                }
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let reliable = self
+            .reliable
+            .as_ref()
+            .filter(|_| self.link.takes_output() && self.sends_data())
+            .and_then(|reliable| reliable.deadline());
+
+        let channels = self.channels.iter().flat_map(|channel| {
+            [channel.retry_at, channel.deadline(&self.bus)]
+                .into_iter()
+                .flatten()
+        });
+
+        [
+            self.link.retry_at,
+            self.link.resync_at,
+            self.link.drain_check_at,
+            reliable,
+            self.secure.as_ref().and_then(|secure| secure.deadline()),
+            self.control.as_ref().map(|control| control.deadline()),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(channels)
+        .min()
+    }
+
+    fn run_timers(&mut self, now: Instant) -> io::Result<()> {
+        if self.link.retry_at.is_some_and(|retry_at| retry_at <= now) {
+            self.reopen_link(now)?;
+        }
+
+        if self
+            .link
+            .resync_at
+            .is_some_and(|resync_at| resync_at <= now)
+        {
+            self.link.resync_at = None;
+
+            if let Err(e) = self.link.transport.clear_input() {
+                eprintln!("Failed to clear buffer: {}", e);
+                self.link_failed(now)?;
+            }
+        }
+
+        if self
+            .link
+            .drain_check_at
+            .is_some_and(|check_at| check_at <= now)
+        {
+            self.link.drain_check_at = None;
+        }
+
+        for index in 0..self.channels.len() {
+            if self.channels[index]
+                .retry_at
+                .is_some_and(|retry_at| retry_at <= now)
+            {
+                self.reopen_channel(index, now);
+            }
+
+            self.channels[index].flush_due(&mut self.bus, now);
+        }
+
+        if let Some(control) = &mut self.control
+            && control.deadline() <= now
+        {
+            control.on_timer(now, &mut self.bus, &mut self.channels);
+        }
+
+        if let Some(secure) = &mut self.secure {
+            secure.on_timer(now);
+        }
+
+        Ok(())
+    }
+
+    // Channel data waits in the queues until the peer authenticated
+    fn sends_data(&self) -> bool {
+        self.secure
+            .as_ref()
+            .is_none_or(|secure| secure.is_authenticated())
+    }
+
+    // Largest payload channels may put in a frame
+    fn max_payload_len(&self) -> usize {
+        match self.secure {
+            Some(_) => self.length.max_payload_len() - SEAL_OVERHEAD,
+            None => self.length.max_payload_len(),
+        }
+    }
+
+    // Tells the peer about channels that were opened or closed since the last iteration
+    fn report_open_channels(&mut self) {
+        for channel in &mut self.channels {
+            let open = channel.is_connected();
+
+            if open == channel.open {
+                continue;
+            }
+
+            channel.open = open;
+
+            if let Some(control) = &mut self.control {
+                control.notify_channel_open(&mut self.bus, channel.id(), open);
+            }
+        }
+    }
+
+    fn add_channel(&mut self, channel: Channel, now: Instant) {
+        self.bus.set_queue(channel.id, channel.queue);
+
+        let channel = ChannelState::new(channel, self.max_payload_len(), &self.stats);
+        let opened = channel.channel.transport.fd().is_some();
+        self.channels.push(channel);
+
+        // Serial ports are opened on first use
+        if !opened {
+            self.reopen_channel(self.channels.len() - 1, now);
+        }
+    }
+
+    fn reopen_channel(&mut self, index: usize, now: Instant) {
+        let channel = &mut self.channels[index];
+
+        match channel.channel.transport.reopen() {
+            Ok(None) => channel.retry_at = None,
+            Ok(Some(delay)) => channel.retry_at = Some(now + delay),
+            Err(e) => {
+                eprintln!("Failed to reopen port {}: {}", channel.id(), e);
+                channel.retry_at = None;
+                channel.closed = true;
+
+                if !channel.output.is_empty() {
+                    channel.output.clear();
+                    channel.stats.record_dropped();
+                }
+            }
+        }
+    }
+
+    fn channel_failed(&mut self, index: usize, now: Instant) {
+        self.channels[index].stats.record_reconnect();
+        self.reopen_channel(index, now);
+    }
+
+    fn write_channels(&mut self, now: Instant) {
+        for index in 0..self.channels.len() {
+            if !self.channels[index].wants_write() || self.channels[index].fd().is_none() {
+                continue;
+            }
+
+            if let Err(e) = self.channels[index].write() {
+                eprintln!(
+                    "Error writing to channel {}: {}. Attempting to reconnect...",
+                    self.channels[index].id(),
+                    e
+                );
+                self.channel_failed(index, now);
+            }
+        }
+    }
+
+    fn reopen_link(&mut self, now: Instant) -> io::Result<()> {
+        self.link.retry_at = self.link.transport.reopen()?.map(|delay| now + delay);
+        Ok(())
+    }
+
+    // Data still waiting in the channel queues is sent once the link is back
+    fn link_failed(&mut self, now: Instant) -> io::Result<()> {
+        self.stats.link.record_reconnect();
+        self.link.decoder.clear();
+        // Unacknowledged frames are retransmitted in reliable mode, the rest of a plain frame is useless
+        self.link.output.clear();
+        self.link.resync_at = None;
+        self.link.drain_check_at = None;
+        self.reopen_link(now)
+    }
+
+    // The plain format cannot find the start of the next frame, so everything buffered is thrown away
+    fn lose_sync(&mut self, reason: &str, now: Instant) -> io::Result<()> {
+        eprintln!(
+            "{}. Assuming we're not in sync! Waiting 1s and trying again...",
+            reason
+        );
+        self.link.decoder.clear();
+        self.stats.link.record_resyncs(1);
+
+        if let Err(e) = self.link.transport.clear_input() {
+            eprintln!("Failed to clear buffer: {}", e);
+            return self.link_failed(now);
+        }
+
+        self.link.resync_at = Some(now + RESYNC_WAIT);
+        Ok(())
+    }
+
+    // Encodes frames until the link's output buffer fills up or there is nothing left to send
+    fn write_link(&mut self, now: Instant) -> io::Result<()> {
+        loop {
+            if self.link.output.is_empty() && !self.take_frames(now) {
+                return Ok(());
+            }
+
+            match self.link.transport.write(&self.link.output) {
+                Ok(0) => {
+                    eprintln!("Multiplexed port does not take any more data");
+                    return self.link_failed(now);
+                }
+                Ok(bytes) => {
+                    self.link.output.drain(..bytes);
+
+                    if !self.link.output.is_empty() {
+                        return Ok(());
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => {
+                    eprintln!("Failed to write to multiplexed port: {}", e);
+                    return self.link_failed(now);
+                }
+            }
+        }
+    }
+
+    // Moves the next frames into the link's output buffer. Returns false if there is nothing to send.
+    fn take_frames(&mut self, now: Instant) -> bool {
+        if !self.link.takes_output() {
+            return false;
+        }
+
+        let handshake = self
+            .secure
+            .as_ref()
+            .is_some_and(|secure| secure.has_output());
+
+        let pending = self.sends_data()
+            && match &self.reliable {
+                Some(reliable) => reliable.has_pending(now) || !self.bus.is_empty(),
+                None => !self.bus.is_empty(),
+            };
+
+        if !handshake && !pending {
+            return false;
+        }
+
+        // Keep the transport's own buffer short, so the scheduler can still put more
+        // important data in front of what is queued
+        if self
+            .link
+            .transport
+            .unsent_bytes()
+            .is_ok_and(|unsent| unsent > OUTPUT_LOW_WATER)
+        {
+            self.link.drain_check_at = Some(now + DRAIN_CHECK_INTERVAL);
+            return false;
+        }
+
+        let mut frames = match &mut self.secure {
+            Some(secure) => secure.take_output(),
+            None => vec![],
+        };
+
+        if pending {
+            match &mut self.reliable {
+                Some(reliable) => frames.extend(reliable.next_frames(&mut self.bus, now)),
+                None => frames.extend(self.bus.next(|_| true).map(|block| Frame {
+                    flags: if block.compressed { FLAG_COMPRESSED } else { 0 },
+                    ..Frame::data(block.id, block.data)
+                })),
+            }
+        }
+
+        let format = self.link.decoder.format();
+
+        for mut frame in frames {
+            #[cfg(debug_assertions)]
+            println!("Sent {} bytes for device {}", frame.payload.len(), frame.id);
+
+            // Captures hold what was sent before encryption
+            if let Some(capture) = &self.capture {
+                capture.record(Direction::Sent, &frame);
+            }
+
+            let payload_len = frame.payload.len();
+
+            if let Some(secure) = &mut self.secure
+                && frame.id != HANDSHAKE_ID
+                && let Err(e) = secure.seal(&mut frame)
+            {
+                eprintln!("{}, dropping frame for device {}", e, frame.id);
+                continue;
+            }
+
+            let buff = match frame.encode(format, self.length) {
+                Ok(buff) => buff,
+                Err(e) => {
+                    eprintln!("{}, dropping frame for device {}", e, frame.id);
+                    continue;
+                }
+            };
+
+            self.stats.link.record_sent(buff.len());
+
+            // Reliable mode counts data when it first enters the window, not on every retransmit
+            if format == FrameFormat::Plain && frame.id != HANDSHAKE_ID {
+                self.stats.channel(frame.id).record_sent(payload_len);
+            }
+
+            self.link.output.extend_from_slice(&buff);
+        }
+
+        !self.link.output.is_empty()
+    }
+
+    fn read_link(&mut self, now: Instant) -> io::Result<()> {
+        let mut buff = [0u8; LINK_READ_LEN];
+
+        let bytes = match self.link.transport.read(&mut buff) {
+            Ok(0) => {
+                eprintln!("Multiplexed port reached end of stream");
+                return self.link_failed(now);
+            }
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => {
+                eprintln!("Failed to read from multiplexed port: {}", e);
+                return self.link_failed(now);
+            }
+        };
+
+        self.stats.link.record_read(bytes);
+        self.link.decoder.push(&buff[..bytes]);
+
+        loop {
+            let frame = match self.link.decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => return self.lose_sync(&e.to_string(), now),
+            };
+
+            self.stats.link.record_frame_received();
+
+            let frame = match &mut self.secure {
+                Some(secure) if frame.id == HANDSHAKE_ID => {
+                    if let Some(capture) = &self.capture {
+                        capture.record(Direction::Received, &frame);
+                    }
+
+                    secure.on_handshake(&frame.payload);
+                    continue;
+                }
+                Some(secure) => {
+                    let id = frame.id;
+
+                    // A corrupted frame fails to open as well. Losing sync shows as unknown ids.
+                    match secure.open(frame) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            eprintln!("{}, dropping frame for device {}", e, id);
+                            self.stats.link.record_dropped();
+                            continue;
+                        }
+                    }
+                }
+                None => frame,
+            };
+
+            if let Some(capture) = &self.capture {
+                capture.record(Direction::Received, &frame);
+            }
+
+            let destination = self.destination(frame.id);
+
+            let frame = match &mut self.reliable {
+                Some(reliable) => match reliable.receive(frame, destination) {
+                    Some(frame) => frame,
+                    None => continue,
+                },
+                None if destination != Destination::Unknown => frame,
+                None => {
+                    let reason = format!("Device with id {} does not exist", frame.id);
+                    return self.lose_sync(&reason, now);
+                }
+            };
+
+            #[cfg(debug_assertions)]
+            println!(
+                "Received {} bytes for device {}",
+                frame.payload.len(),
+                frame.id
+            );
+
+            self.deliver(frame, now);
+        }
+
+        self.stats
+            .link
+            .record_resyncs(self.link.decoder.take_resyncs());
+
+        Ok(())
+    }
+
+    fn destination(&self, id: u8) -> Destination {
+        if (id == CONTROL_CHANNEL_ID && self.control.is_some())
+            || (id == HANDSHAKE_ID && self.secure.is_some())
+        {
+            return Destination::Ready;
+        }
+
+        match self.channels.iter().find(|channel| channel.id() == id) {
+            Some(channel) if channel.output_full() => Destination::Full,
+            Some(_) => Destination::Ready,
+            None => Destination::Unknown,
+        }
+    }
+
+    // Hands data from the peer to the channel it is for
+    fn deliver(&mut self, frame: Frame, now: Instant) {
+        let id = frame.id;
+
+        if id == CONTROL_CHANNEL_ID
+            && let Some(control) = &mut self.control
+        {
+            self.stats.channel(id).record_received(frame.payload.len());
+            let created = control.on_message(&frame.payload, &mut self.bus, &mut self.channels);
+
+            for channel in created {
+                self.add_channel(channel, now);
+            }
+
+            return;
+        }
+
+        let Some(channel) = self.channels.iter_mut().find(|channel| channel.id() == id) else {
+            return;
+        };
+
+        if channel.closed {
+            eprintln!("Device with id {} is closed, dropping frame", id);
+            channel.stats.record_dropped();
+            return;
+        }
+
+        // Only without reliable mode, which leaves the frame for later instead
+        if channel.output_full() {
+            eprintln!("Device with id {} is not taking data, dropping frame", id);
+            channel.stats.record_dropped();
+            return;
+        }
+
+        channel.stats.record_received(frame.payload.len());
+
+        if frame.flags & FLAG_COMPRESSED == 0 {
+            channel.output.extend(frame.payload);
+            return;
+        }
+
+        match decompress(&frame.payload, self.length.max_payload_len()) {
+            Ok(payload) => channel.output.extend(payload),
+            Err(e) => {
+                eprintln!("{} for device {}, dropping frame", e, id);
+                channel.stats.record_dropped();
+            }
+        }
+    }
+}
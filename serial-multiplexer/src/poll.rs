@@ -0,0 +1,155 @@
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Makes reads and writes on `fd` return `WouldBlock` instead of waiting.
+pub fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: plain fcntl calls on a descriptor the caller owns
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read_fd(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: buf is valid for writes of its length
+    let bytes = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+
+    if bytes < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(bytes as usize)
+}
+
+pub fn write_fd(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    // SAFETY: buf is valid for reads of its length
+    let bytes = unsafe { libc::write(fd, buf.as_ptr().cast(), buf.len()) };
+
+    if bytes < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(bytes as usize)
+}
+
+/// The descriptors the event loop waits on in one iteration.
+///
+/// Built from scratch before every wait, so what is waited for always follows the current state,
+/// e.g. a channel whose queue is full is simply left out.
+#[derive(Default)]
+pub(crate) struct PollSet {
+    fds: Vec<libc::pollfd>,
+}
+
+impl PollSet {
+    pub fn clear(&mut self) {
+        self.fds.clear();
+    }
+
+    /// Returns the index to check readiness with after [`wait`](PollSet::wait).
+    pub fn add(&mut self, fd: RawFd, read: bool, write: bool) -> usize {
+        let mut events = 0;
+
+        if read {
+            events |= libc::POLLIN;
+        }
+
+        if write {
+            events |= libc::POLLOUT;
+        }
+
+        self.fds.push(libc::pollfd {
+            fd,
+            events,
+            revents: 0,
+        });
+
+        self.fds.len() - 1
+    }
+
+    /// Waits until a descriptor is ready or the timeout passed. `None` waits forever.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        // Round up, waking up early just to find nothing due would spin
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout
+                .as_micros()
+                .div_ceil(1000)
+                .min(libc::c_int::MAX as u128) as libc::c_int
+        });
+
+        // SAFETY: the pointer and length describe our own vector
+        let result = unsafe {
+            libc::poll(
+                self.fds.as_mut_ptr(),
+                self.fds.len() as libc::nfds_t,
+                timeout,
+            )
+        };
+
+        if result < 0 {
+            let error = io::Error::last_os_error();
+
+            // A signal handler ran, the caller checks for whatever it did
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Readable, or failed. Either way a read tells what happened.
+    pub fn readable(&self, index: usize) -> bool {
+        self.fds[index].revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR | libc::POLLNVAL)
+            != 0
+    }
+}
+
+/// Wakes up the event loop from another thread.
+#[derive(Clone)]
+pub struct Waker {
+    writer: Arc<PipeWriter>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        // A full pipe means a wake up is pending anyway
+        let _ = (&*self.writer).write(&[0]);
+    }
+}
+
+/// The end of a [`Waker`] the event loop waits on.
+pub(crate) struct WakeReceiver {
+    reader: PipeReader,
+}
+
+impl WakeReceiver {
+    pub fn fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+
+    pub fn drain(&mut self) {
+        let mut buff = [0u8; 64];
+        while self.reader.read(&mut buff).is_ok_and(|bytes| bytes > 0) {}
+    }
+}
+
+pub(crate) fn waker() -> io::Result<(Waker, WakeReceiver)> {
+    let (reader, writer) = io::pipe()?;
+    set_nonblocking(reader.as_raw_fd())?;
+    set_nonblocking(writer.as_raw_fd())?;
+
+    Ok((
+        Waker {
+            writer: Arc::new(writer),
+        },
+        WakeReceiver { reader },
+    ))
+}
@@ -0,0 +1,111 @@
+use std::ffi::CString;
+use std::fs::{create_dir_all, read_link, remove_file};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Mutex;
+
+use serialport::{SerialPort, TTYPort};
+
+use crate::signal::on_signal;
+
+/// Where a virtual port is linked and who may open it.
+#[derive(Clone, Debug)]
+pub struct PtySettings {
+    pub link_path: PathBuf,
+    /// Permissions of the pty slave, e.g. 0o660
+    pub mode: Option<u32>,
+    /// Group owning the pty slave
+    pub gid: Option<u32>,
+}
+
+// Links created by this process, with the slave they point to, removed again on shutdown
+static LINKS: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(vec![]);
+
+/// Creates a pty and links its slave at `settings.link_path`.
+///
+/// The master is returned, the slave has to stay open so the pty survives clients closing it.
+pub fn create_pty(settings: &PtySettings) -> io::Result<(TTYPort, TTYPort)> {
+    let (mut master, slave) = TTYPort::pair()?;
+    master.set_timeout(std::time::Duration::MAX)?;
+
+    let slave_name = PathBuf::from(
+        slave
+            .name()
+            .ok_or_else(|| io::Error::other("pty slave has no name"))?,
+    );
+
+    // SAFETY: plain syscalls on a descriptor we own
+    unsafe {
+        if let Some(gid) = settings.gid
+            && libc::fchown(slave.as_raw_fd(), u32::MAX, gid) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        if let Some(mode) = settings.mode
+            && libc::fchmod(slave.as_raw_fd(), mode) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some(link_dir) = settings.link_path.parent() {
+        create_dir_all(link_dir)?;
+    }
+
+    let _ = remove_file(&settings.link_path);
+    symlink(&slave_name, &settings.link_path)?;
+
+    LINKS
+        .lock()
+        .unwrap()
+        .push((settings.link_path.clone(), slave_name));
+
+    Ok((master, slave))
+}
+
+/// Removes the links created so far. Links replaced by someone else in the meantime are left alone.
+pub fn remove_links() {
+    for (link_path, slave_name) in LINKS.lock().unwrap().drain(..) {
+        if read_link(&link_path).is_ok_and(|target| target == slave_name) {
+            let _ = remove_file(&link_path);
+        }
+    }
+}
+
+/// Removes the links and exits on SIGINT or SIGTERM.
+pub fn remove_links_on_signal() {
+    on_signal(|signal| {
+        println!("Received signal {}, removing links", signal);
+        remove_links();
+        exit(128 + signal);
+    });
+}
+
+/// Parses an octal file mode such as `660` or `0o660`.
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("'{}' is not an octal file mode like 660", mode)),
+    }
+}
+
+/// Looks up a group by name or number.
+pub fn resolve_group(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+
+    let name = CString::new(group).ok()?;
+
+    // SAFETY: getgrnam returns null or a pointer to a static entry, read right away
+    unsafe {
+        let entry = libc::getgrnam(name.as_ptr());
+        (!entry.is_null()).then(|| (*entry).gr_gid)
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::channel::{Ack, DataBlock};
+use crate::frame::{FLAG_ACK, FLAG_COMPRESSED, FLAG_NEED_SYNC, FLAG_SYNC, Frame};
+use crate::scheduler::MainBus;
+use crate::stats::{ChannelStats, Stats};
+
+// Sequence numbers are a single byte, so the window must stay below half the sequence space
+pub const MAX_WINDOW_SIZE: u8 = 127;
+
+#[derive(Clone, Copy)]
+pub struct ReliableSettings {
+    pub window_size: u8,
+    pub retransmit_timeout: Duration,
+}
+
+struct InFlight {
+    frame: Frame,
+    sent_at: Instant,
+}
+
+// Go-back-N sender state for a single channel
+struct TxWindow {
+    id: u8,
+    stats: Arc<ChannelStats>,
+    base: u8,
+    next_seq: u8,
+    in_flight: VecDeque<InFlight>,
+    backlog: VecDeque<DataBlock>,
+    synced: bool,
+}
+
+impl TxWindow {
+    fn new(id: u8, stats: Arc<ChannelStats>) -> Self {
+        TxWindow {
+            id,
+            stats,
+            base: 0,
+            next_seq: 0,
+            in_flight: VecDeque::new(),
+            backlog: VecDeque::new(),
+            synced: false,
+        }
+    }
+
+    fn in_flight_count(&self) -> u8 {
+        self.next_seq.wrapping_sub(self.base)
+    }
+
+    fn push(&mut self, flags: u8, payload: Vec<u8>) -> &Frame {
+        let frame = Frame {
+            id: self.id,
+            flags,
+            seq: self.next_seq,
+            payload,
+        };
+
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.in_flight.push_back(InFlight {
+            frame,
+            sent_at: Instant::now(),
+        });
+
+        &self.in_flight.back().unwrap().frame
+    }
+
+    // Returns the frames that may be sent right now
+    fn fill(&mut self, window_size: u8) -> Vec<Frame> {
+        let mut out = vec![];
+
+        if !self.synced {
+            if self.in_flight.is_empty() {
+                out.push(self.push(FLAG_SYNC, vec![]).clone());
+            }
+
+            return out;
+        }
+
+        while self.in_flight_count() < window_size {
+            let block = match self.backlog.pop_front() {
+                Some(block) => block,
+                None => break,
+            };
+
+            let flags = if block.compressed { FLAG_COMPRESSED } else { 0 };
+            self.stats.record_sent(block.data.len());
+            out.push(self.push(flags, block.data).clone());
+        }
+
+        out
+    }
+
+    fn acknowledge(&mut self, seq: u8) {
+        let acked = seq.wrapping_sub(self.base);
+
+        if acked == 0 || acked > self.in_flight_count() {
+            // Duplicate or stale ACK
+            return;
+        }
+
+        for _ in 0..acked {
+            self.in_flight.pop_front();
+        }
+
+        self.base = seq;
+        self.synced = true;
+    }
+
+    // The peer lost its state for this channel. Requeue everything and start over with a SYNC.
+    fn reset(&mut self) {
+        self.stats.record_resyncs(1);
+
+        while let Some(in_flight) = self.in_flight.pop_back() {
+            let frame = in_flight.frame;
+
+            if frame.flags & FLAG_SYNC == 0 {
+                self.backlog.push_front(DataBlock {
+                    id: frame.id,
+                    compressed: frame.flags & FLAG_COMPRESSED != 0,
+                    data: frame.payload,
+                });
+            }
+        }
+
+        self.base = self.next_seq;
+        self.synced = false;
+    }
+
+    fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.in_flight.front().map(|f| f.sent_at + timeout)
+    }
+
+    fn retransmit(&mut self) -> Vec<Frame> {
+        let now = Instant::now();
+        self.stats.record_retransmits(self.in_flight.len());
+
+        self.in_flight
+            .iter_mut()
+            .map(|f| {
+                f.sent_at = now;
+                f.frame.clone()
+            })
+            .collect()
+    }
+}
+
+/// Where a received frame would go
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Destination {
+    /// No channel with the frame's id exists here
+    Unknown,
+    /// The channel has no room for more data until its transport takes some
+    Full,
+    Ready,
+}
+
+/// Reliable mode state of the multiplexed link: a Go-back-N window per channel we send on, and
+/// the next sequence number we expect per channel the peer sends on.
+pub(crate) struct ReliableLink {
+    settings: ReliableSettings,
+    windows: HashMap<u8, TxWindow>,
+    // Missing means we never saw a SYNC
+    expected: HashMap<u8, u8>,
+    // ACKs are tiny and hold up every channel when they are late, they skip the queues
+    acks: VecDeque<Ack>,
+    stats: Arc<Stats>,
+}
+
+impl ReliableLink {
+    pub fn new(settings: ReliableSettings, stats: Arc<Stats>) -> Self {
+        ReliableLink {
+            settings,
+            windows: HashMap::new(),
+            expected: HashMap::new(),
+            acks: VecDeque::new(),
+            stats,
+        }
+    }
+
+    /// When the oldest unacknowledged frame of any channel is due to be sent again
+    pub fn deadline(&self) -> Option<Instant> {
+        self.windows
+            .values()
+            .filter_map(|w| w.deadline(self.settings.retransmit_timeout))
+            .min()
+    }
+
+    /// Whether [`next_frames`](ReliableLink::next_frames) has something without taking from the bus.
+    pub fn has_pending(&self, now: Instant) -> bool {
+        !self.acks.is_empty()
+            || self.deadline().is_some_and(|deadline| deadline <= now)
+            || self.windows.values().any(|window| {
+                !window.backlog.is_empty() || (!window.synced && window.in_flight.is_empty())
+            })
+    }
+
+    /// Frames to send next: ACKs, then retransmissions that are due, then new data.
+    pub fn next_frames(&mut self, bus: &mut MainBus, now: Instant) -> Vec<Frame> {
+        let mut frames: Vec<Frame> = self
+            .acks
+            .drain(..)
+            .map(|ack| Frame {
+                id: ack.id,
+                flags: if ack.need_sync {
+                    FLAG_ACK | FLAG_NEED_SYNC
+                } else {
+                    FLAG_ACK
+                },
+                seq: ack.seq,
+                payload: vec![],
+            })
+            .collect();
+
+        for window in self.windows.values_mut() {
+            if let Some(deadline) = window.deadline(self.settings.retransmit_timeout)
+                && deadline <= now
+            {
+                #[cfg(debug_assertions)]
+                println!(
+                    "Retransmitting {} frames for device {}",
+                    window.in_flight.len(),
+                    window.id
+                );
+                frames.extend(window.retransmit());
+            }
+
+            // Room freed up by ACKs, or a SYNC after the peer asked for one
+            frames.extend(window.fill(self.settings.window_size));
+        }
+
+        if !frames.is_empty() {
+            return frames;
+        }
+
+        // Only take data for channels that can use it, the rest waits its turn on the main bus
+        let windows = &self.windows;
+        let Some(block) = bus.next(|id| {
+            windows
+                .get(&id)
+                .is_none_or(|window| window.backlog.is_empty())
+        }) else {
+            return frames;
+        };
+
+        let window = self
+            .windows
+            .entry(block.id)
+            .or_insert_with(|| TxWindow::new(block.id, self.stats.channel(block.id)));
+        window.backlog.push_back(block);
+        window.fill(self.settings.window_size)
+    }
+
+    /// Handles a frame from the peer. Returns data frames that arrived in order.
+    ///
+    /// Frames for unknown channels are acknowledged and dropped, so the peer doesn't retransmit
+    /// them forever. Frames for a full channel aren't acknowledged, the peer sends them again.
+    pub fn receive(&mut self, frame: Frame, destination: Destination) -> Option<Frame> {
+        if frame.flags & FLAG_ACK != 0 {
+            if let Some(window) = self.windows.get_mut(&frame.id) {
+                if frame.flags & FLAG_NEED_SYNC != 0 {
+                    #[cfg(debug_assertions)]
+                    println!("Peer requested resync for device {}", frame.id);
+                    window.reset();
+                } else {
+                    window.acknowledge(frame.seq);
+                }
+            }
+
+            return None;
+        }
+
+        if destination == Destination::Unknown {
+            eprintln!("Device with id {} does not exist, dropping frame", frame.id);
+            self.stats.link.record_dropped();
+            self.acks.push_back(Ack {
+                id: frame.id,
+                seq: frame.seq.wrapping_add(1),
+                need_sync: false,
+            });
+            return None;
+        }
+
+        if frame.flags & FLAG_SYNC != 0 {
+            self.stats.channel(frame.id).record_resyncs(1);
+            let next = frame.seq.wrapping_add(1);
+            self.expected.insert(frame.id, next);
+            self.acks.push_back(Ack {
+                id: frame.id,
+                seq: next,
+                need_sync: false,
+            });
+            return None;
+        }
+
+        let Some(next) = self.expected.get_mut(&frame.id) else {
+            self.acks.push_back(Ack {
+                id: frame.id,
+                seq: 0,
+                need_sync: true,
+            });
+            return None;
+        };
+
+        let in_order = frame.seq == *next && destination != Destination::Full;
+
+        if in_order {
+            *next = next.wrapping_add(1);
+        }
+
+        // Out of order frames and frames the channel has no room for are dropped, the cumulative
+        // ACK makes the peer go back
+        self.acks.push_back(Ack {
+            id: frame.id,
+            seq: *next,
+            need_sync: false,
+        });
+
+        in_order.then_some(frame)
+    }
+}
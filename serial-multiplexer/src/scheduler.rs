@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::channel::DataBlock;
+use crate::stats::{ChannelStats, Stats};
+
+// Bytes a channel of weight 1 may send per round before the next channel of the same priority gets a turn
+const QUANTUM: usize = 256;
+
+/// What happens to data read from a channel while its queue on the main bus is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Stop reading from the channel until there is room again
+    Block,
+    /// Throw away the oldest queued block to make room
+    DropOldest,
+    /// Throw away the block that did not fit
+    DropNewest,
+}
+
+/// How a channel's traffic is queued and scheduled on the multiplexed link.
+#[derive(Clone, Copy, Debug)]
+pub struct QueueSettings {
+    /// Channels with a higher priority are always sent first
+    pub priority: u8,
+    /// Share of the link relative to other channels with the same priority
+    pub weight: u32,
+    /// Number of blocks that may wait to be sent
+    pub capacity: usize,
+    pub policy: DropPolicy,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        QueueSettings {
+            priority: 0,
+            weight: 1,
+            capacity: 64,
+            policy: DropPolicy::Block,
+        }
+    }
+}
+
+struct Queue {
+    id: u8,
+    settings: QueueSettings,
+    blocks: VecDeque<DataBlock>,
+    // Bytes this queue may still send in its current turn
+    deficit: usize,
+    dropping: bool,
+    stats: Arc<ChannelStats>,
+}
+
+impl Queue {
+    fn new(id: u8, settings: QueueSettings, stats: Arc<ChannelStats>) -> Self {
+        Queue {
+            id,
+            settings,
+            blocks: VecDeque::new(),
+            deficit: 0,
+            dropping: false,
+            stats,
+        }
+    }
+}
+
+/// The queues between the channels and the multiplexed link.
+///
+/// The link picks the next data block by channel priority and weight: strict priority between
+/// priority levels, deficit round robin within one. Each channel's queue is bounded.
+pub struct MainBus {
+    queues: Vec<Queue>,
+    // Queue whose turn it is, and whether it already got its quantum for this turn
+    cursor: usize,
+    in_turn: bool,
+    stats: Arc<Stats>,
+}
+
+impl MainBus {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        MainBus {
+            queues: vec![],
+            cursor: 0,
+            in_turn: false,
+            stats,
+        }
+    }
+
+    fn queue_mut(&mut self, id: u8) -> &mut Queue {
+        let index = match self.queues.iter().position(|queue| queue.id == id) {
+            Some(index) => index,
+            None => {
+                let stats = self.stats.channel(id);
+                self.queues
+                    .push(Queue::new(id, QueueSettings::default(), stats));
+                self.queues.len() - 1
+            }
+        };
+
+        &mut self.queues[index]
+    }
+
+    /// Sets how a channel is scheduled. Channels without settings use the defaults.
+    pub fn set_queue(&mut self, id: u8, settings: QueueSettings) {
+        self.queue_mut(id).settings = settings;
+    }
+
+    /// Whether a block for `id` would be queued without dropping anything.
+    ///
+    /// Channels with [`DropPolicy::Block`] are not read from while this is false.
+    pub fn has_room(&self, id: u8) -> bool {
+        self.queues
+            .iter()
+            .find(|queue| queue.id == id)
+            .is_none_or(|queue| queue.blocks.len() < queue.settings.capacity.max(1))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.blocks.is_empty())
+    }
+
+    /// Queues a block. Blocks dropped because of a full queue are counted as dropped in the
+    /// channel's stats.
+    ///
+    /// A full queue that blocks takes the block anyway, its channel stops being read instead.
+    pub fn send(&mut self, block: DataBlock) {
+        let queue = self.queue_mut(block.id);
+
+        if queue.blocks.len() < queue.settings.capacity.max(1)
+            || queue.settings.policy == DropPolicy::Block
+        {
+            queue.blocks.push_back(block);
+            queue.stats.set_queue_depth(queue.blocks.len());
+            return;
+        }
+
+        if !queue.dropping {
+            eprintln!(
+                "Queue for device {} is full, dropping data until it drains",
+                queue.id
+            );
+            queue.dropping = true;
+        }
+
+        queue.stats.record_dropped();
+
+        if queue.settings.policy == DropPolicy::DropOldest {
+            queue.blocks.pop_front();
+            queue.blocks.push_back(block);
+        }
+    }
+
+    /// Takes the next block to send. Data for channels `eligible` rejects stays queued.
+    pub fn next(&mut self, eligible: impl Fn(u8) -> bool) -> Option<DataBlock> {
+        let is_candidate = |queue: &Queue| !queue.blocks.is_empty() && eligible(queue.id);
+
+        let priority = self
+            .queues
+            .iter()
+            .filter(|queue| is_candidate(queue))
+            .map(|queue| queue.settings.priority)
+            .max()?;
+
+        loop {
+            let index = self.cursor % self.queues.len();
+            let queue = &mut self.queues[index];
+
+            if is_candidate(queue) && queue.settings.priority == priority {
+                if !self.in_turn {
+                    queue.deficit += QUANTUM * queue.settings.weight.max(1) as usize;
+                    self.in_turn = true;
+                }
+
+                let len = queue.blocks.front().map_or(0, |block| block.data.len());
+
+                if queue.deficit >= len {
+                    queue.deficit -= len;
+                    let block = queue.blocks.pop_front();
+                    queue.stats.set_queue_depth(queue.blocks.len());
+
+                    if queue.blocks.is_empty() {
+                        queue.deficit = 0;
+                        queue.dropping = false;
+                        self.cursor = index + 1;
+                        self.in_turn = false;
+                    }
+
+                    return block;
+                }
+            }
+
+            self.cursor = index + 1;
+            self.in_turn = false;
+        }
+    }
+
+    /// Throws away everything queued, counting it as dropped.
+    pub fn clear(&mut self) {
+        for queue in &mut self.queues {
+            for _ in queue.blocks.drain(..) {
+                queue.stats.record_dropped();
+            }
+
+            queue.stats.set_queue_depth(0);
+            queue.deficit = 0;
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::frame::Frame;
+
+// Handshake frames are sent in the clear under HANDSHAKE_ID:
+// [TYPE_HELLO][reply][nonce; 16]
+// [TYPE_CONFIRM][counter; 4][tag; 16]
+//
+// Each end picks a random nonce on startup. Frames are encrypted with ChaCha20-Poly1305 under a
+// key per direction, derived from the pre-shared key, both nonces and the direction. A confirm is an empty
+// sealed message that proves the sender derived the same keys, i.e. knows the pre-shared key.
+// Nothing but handshake frames is sent or accepted until the peer confirmed. A hello with a new
+// nonce, e.g. after the peer restarted, only replaces the current session once it is confirmed.
+//
+// Sealed payload layout:
+// [counter; 4][ciphertext][tag; 16]
+// The counter is the AEAD nonce and must grow with every frame, anything else is a replay.
+// Frame id, flags and sequence number are authenticated as associated data.
+pub const HANDSHAKE_ID: u8 = 0xFD;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 16;
+const COUNTER_LEN: usize = 4;
+const TAG_LEN: usize = 16;
+/// Bytes a sealed payload adds to the frame
+pub const SEAL_OVERHEAD: usize = COUNTER_LEN + TAG_LEN;
+
+const TYPE_HELLO: u8 = 1;
+const TYPE_CONFIRM: u8 = 2;
+
+// Counter of the confirm, data frames start after it
+const CONFIRM_COUNTER: u32 = 0;
+const CONFIRM_AAD: [u8; 2] = [HANDSHAKE_ID, TYPE_CONFIRM];
+
+// How often the hello is repeated until the peer confirmed
+const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sessions the peer may start before we pick a new nonce, see SecureLink::used
+const MAX_USED_NONCES: usize = 64;
+
+const KEY_INFO: &[u8] = b"serial-multiplexer frame key";
+// Appended to KEY_INFO. The end with the lower nonce is a.
+const INFO_A_TO_B: &[u8] = b" a->b";
+const INFO_B_TO_A: &[u8] = b" b->a";
+
+#[derive(Error, Debug)]
+pub enum KeyError {
+    #[error("Key must be {digits} hex digits, found {0} characters", digits = KEY_LEN * 2)]
+    WrongLength(usize),
+    #[error("Key must only contain hex digits")]
+    NotHex,
+}
+
+#[derive(Error, Debug)]
+pub enum SecureError {
+    #[error("Peer has not authenticated yet")]
+    NotAuthenticated,
+    #[error("Sealed payload is truncated")]
+    Truncated,
+    #[error("Frame {0} was already received")]
+    Replayed(u32),
+    #[error("Frame failed authentication")]
+    Forged,
+}
+
+/// Key both ends of the link must have to talk to each other.
+#[derive(Clone)]
+pub struct PresharedKey([u8; KEY_LEN]);
+
+impl PresharedKey {
+    /// Parses 64 hex digits, e.g. from `openssl rand -hex 32`. Surrounding whitespace is ignored.
+    pub fn from_hex(text: &str) -> Result<Self, KeyError> {
+        let text = text.trim();
+
+        if !text.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(KeyError::NotHex);
+        }
+
+        if text.len() != KEY_LEN * 2 {
+            return Err(KeyError::WrongLength(text.len()));
+        }
+
+        let mut key = [0u8; KEY_LEN];
+
+        for (index, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&text[2 * index..2 * index + 2], 16)
+                .map_err(|_| KeyError::NotHex)?;
+        }
+
+        Ok(PresharedKey(key))
+    }
+
+    // Key for the frames sent by the end that picked `from`. The nonces must differ, or both
+    // directions would get the same key.
+    fn derive(&self, from: &[u8; NONCE_LEN], to: &[u8; NONCE_LEN]) -> ChaCha20Poly1305 {
+        let mut salt = [0u8; 2 * NONCE_LEN];
+        salt[..NONCE_LEN].copy_from_slice(from);
+        salt[NONCE_LEN..].copy_from_slice(to);
+
+        let direction = if from < to { INFO_A_TO_B } else { INFO_B_TO_A };
+        let info = [KEY_INFO, direction].concat();
+
+        let mut key = [0u8; KEY_LEN];
+        Hkdf::<Sha256>::new(Some(&salt), &self.0)
+            .expand(&info, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+}
+
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    // SAFETY: nonce is valid for writes of its length
+    let len = unsafe { libc::getrandom(nonce.as_mut_ptr().cast(), NONCE_LEN, 0) };
+    assert_eq!(len, NONCE_LEN as isize, "Failed to get random bytes");
+    nonce
+}
+
+fn aead_nonce(counter: u32) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn seal(cipher: &ChaCha20Poly1305, counter: u32, aad: &[u8], payload: &mut Vec<u8>) {
+    let tag = cipher
+        .encrypt_in_place_detached(&aead_nonce(counter), aad, payload)
+        .expect("Frame payloads are far below the ChaCha20-Poly1305 limit");
+
+    payload.splice(0..0, counter.to_be_bytes());
+    payload.extend_from_slice(&tag);
+}
+
+// Returns the counter and the plain payload
+fn open(
+    cipher: &ChaCha20Poly1305,
+    aad: &[u8],
+    mut payload: Vec<u8>,
+) -> Result<(u32, Vec<u8>), SecureError> {
+    if payload.len() < SEAL_OVERHEAD {
+        return Err(SecureError::Truncated);
+    }
+
+    let counter = u32::from_be_bytes(payload[..COUNTER_LEN].try_into().unwrap());
+    let tag = Tag::clone_from_slice(&payload[payload.len() - TAG_LEN..]);
+    payload.truncate(payload.len() - TAG_LEN);
+    payload.drain(..COUNTER_LEN);
+
+    cipher
+        .decrypt_in_place_detached(&aead_nonce(counter), aad, &mut payload, &tag)
+        .map_err(|_| SecureError::Forged)?;
+
+    Ok((counter, payload))
+}
+
+struct Session {
+    peer_nonce: [u8; NONCE_LEN],
+    tx: ChaCha20Poly1305,
+    rx: ChaCha20Poly1305,
+    // Next counter to send
+    tx_counter: u32,
+    // Last counter received
+    rx_counter: u32,
+}
+
+impl Session {
+    fn new(key: &PresharedKey, nonce: &[u8; NONCE_LEN], peer_nonce: [u8; NONCE_LEN]) -> Self {
+        Session {
+            peer_nonce,
+            tx: key.derive(nonce, &peer_nonce),
+            rx: key.derive(&peer_nonce, nonce),
+            tx_counter: CONFIRM_COUNTER + 1,
+            rx_counter: CONFIRM_COUNTER,
+        }
+    }
+
+    fn confirm(&self) -> Frame {
+        let mut payload = vec![];
+        seal(&self.tx, CONFIRM_COUNTER, &CONFIRM_AAD, &mut payload);
+        payload.insert(0, TYPE_CONFIRM);
+        Frame::data(HANDSHAKE_ID, payload)
+    }
+}
+
+// Authenticated encryption of the multiplexed link with a pre-shared key
+pub(crate) struct SecureLink {
+    key: PresharedKey,
+    nonce: [u8; NONCE_LEN],
+    // Peer nonces of sessions confirmed with our current nonce. Accepting one again would let its
+    // frames be replayed. Hellos nobody confirmed don't count, so injecting them doesn't grow it.
+    used: HashSet<[u8; NONCE_LEN]>,
+    // The confirmed session frames are sealed and opened with
+    session: Option<Session>,
+    // Session of the latest hello, until the peer confirms it. Only then it replaces `session`,
+    // so a hello anyone can inject doesn't cut the link.
+    pending: Option<Session>,
+    // Handshake frames waiting to be sent
+    outbox: Vec<Frame>,
+    next_hello: Instant,
+}
+
+impl SecureLink {
+    pub fn new(key: PresharedKey) -> Self {
+        SecureLink {
+            key,
+            nonce: random_nonce(),
+            used: HashSet::new(),
+            session: None,
+            pending: None,
+            outbox: vec![],
+            next_hello: Instant::now(),
+        }
+    }
+
+    /// Whether the peer proved it has the key. Only handshake frames are sent before.
+    pub fn is_authenticated(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// When the hello has to be repeated
+    pub fn deadline(&self) -> Option<Instant> {
+        (!self.is_authenticated()).then_some(self.next_hello)
+    }
+
+    pub fn on_timer(&mut self, now: Instant) {
+        if self.is_authenticated() || now < self.next_hello {
+            return;
+        }
+
+        self.next_hello = now + HELLO_INTERVAL;
+        self.send_hello(true);
+
+        if let Some(pending) = &self.pending {
+            self.outbox.push(pending.confirm());
+        }
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.outbox.is_empty()
+    }
+
+    pub fn take_output(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn send_hello(&mut self, reply: bool) {
+        let mut payload = vec![TYPE_HELLO, reply as u8];
+        payload.extend_from_slice(&self.nonce);
+        self.outbox.push(Frame::data(HANDSHAKE_ID, payload));
+    }
+
+    /// Handles a handshake frame from the peer.
+    pub fn on_handshake(&mut self, payload: &[u8]) {
+        match payload.split_first() {
+            Some((&TYPE_HELLO, [reply, nonce @ ..])) if nonce.len() == NONCE_LEN => {
+                self.on_hello(nonce.try_into().unwrap(), *reply != 0)
+            }
+            Some((&TYPE_CONFIRM, sealed)) => self.on_confirm(sealed),
+            _ => eprintln!("Dropping malformed handshake frame"),
+        }
+    }
+
+    fn on_hello(&mut self, peer_nonce: [u8; NONCE_LEN], reply: bool) {
+        // Our own hello sent back, a confirm for it would open with our own key
+        if peer_nonce == self.nonce {
+            eprintln!("Peer sent our own handshake back, ignoring it");
+            return;
+        }
+
+        let confirm = match (&self.session, &self.pending) {
+            // The peer missed our confirm
+            (Some(session), _) if session.peer_nonce == peer_nonce => session.confirm(),
+            (_, Some(pending)) if pending.peer_nonce == peer_nonce => pending.confirm(),
+            _ => {
+                if self.used.contains(&peer_nonce) {
+                    eprintln!("Peer repeated an old handshake, ignoring it");
+                    return;
+                }
+
+                #[cfg(debug_assertions)]
+                println!("Peer started a new session");
+
+                let pending = Session::new(&self.key, &self.nonce, peer_nonce);
+                let confirm = pending.confirm();
+                self.pending = Some(pending);
+                confirm
+            }
+        };
+
+        if reply {
+            self.send_hello(false);
+        }
+
+        self.outbox.push(confirm);
+    }
+
+    fn on_confirm(&mut self, sealed: &[u8]) {
+        let confirms = |session: &Option<Session>| {
+            session.as_ref().is_some_and(|session| {
+                matches!(
+                    open(&session.rx, &CONFIRM_AAD, sealed.to_vec()),
+                    Ok((CONFIRM_COUNTER, _))
+                )
+            })
+        };
+
+        if confirms(&self.pending) {
+            let session = self.pending.take().unwrap();
+
+            if self.used.len() >= MAX_USED_NONCES {
+                // Rare enough to start over, and remembering every peer restart is not an option
+                self.renew();
+                return;
+            }
+
+            println!("Peer authenticated");
+            self.used.insert(session.peer_nonce);
+            self.session = Some(session);
+        } else if !confirms(&self.session) {
+            eprintln!("Peer failed to authenticate, make sure both ends use the same key");
+        }
+    }
+
+    // Starts over with a new nonce, before the counter would repeat
+    fn renew(&mut self) {
+        println!("Renewing session keys");
+        self.nonce = random_nonce();
+        self.used.clear();
+        self.session = None;
+        self.pending = None;
+        self.next_hello = Instant::now();
+    }
+
+    /// Encrypts the payload of a frame to be sent. Called for every transmission, including retransmits.
+    pub fn seal(&mut self, frame: &mut Frame) -> Result<(), SecureError> {
+        let Some(session) = &mut self.session else {
+            return Err(SecureError::NotAuthenticated);
+        };
+
+        let aad = [frame.id, frame.flags, frame.seq];
+        seal(&session.tx, session.tx_counter, &aad, &mut frame.payload);
+        session.tx_counter += 1;
+
+        if session.tx_counter == u32::MAX {
+            self.renew();
+        }
+
+        Ok(())
+    }
+
+    /// Decrypts the payload of a received frame, rejecting forged and replayed frames.
+    pub fn open(&mut self, frame: Frame) -> Result<Frame, SecureError> {
+        let Some(session) = &mut self.session else {
+            return Err(SecureError::NotAuthenticated);
+        };
+
+        let aad = [frame.id, frame.flags, frame.seq];
+        let (counter, payload) = open(&session.rx, &aad, frame.payload)?;
+
+        if counter <= session.rx_counter {
+            return Err(SecureError::Replayed(counter));
+        }
+
+        session.rx_counter = counter;
+        Ok(Frame { payload, ..frame })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> PresharedKey {
+        PresharedKey::from_hex(&"42".repeat(KEY_LEN)).unwrap()
+    }
+
+    // Delivers the handshake frames between both ends until neither has anything to send
+    fn exchange(a: &mut SecureLink, b: &mut SecureLink) {
+        while a.has_output() || b.has_output() {
+            for frame in a.take_output() {
+                b.on_handshake(&frame.payload);
+            }
+            for frame in b.take_output() {
+                a.on_handshake(&frame.payload);
+            }
+        }
+    }
+
+    fn connected() -> (SecureLink, SecureLink) {
+        let mut a = SecureLink::new(key());
+        let mut b = SecureLink::new(key());
+        a.on_timer(Instant::now());
+        exchange(&mut a, &mut b);
+        assert!(a.is_authenticated() && b.is_authenticated());
+        (a, b)
+    }
+
+    fn assert_data_passes(from: &mut SecureLink, to: &mut SecureLink) {
+        let mut frame = Frame::data(1, b"data".to_vec());
+        from.seal(&mut frame).unwrap();
+        assert_eq!(to.open(frame).unwrap().payload, b"data");
+    }
+
+    fn injected_hello(nonce: [u8; NONCE_LEN]) -> Vec<u8> {
+        let mut payload = vec![TYPE_HELLO, 1];
+        payload.extend_from_slice(&nonce);
+        payload
+    }
+
+    #[test]
+    fn injected_hellos_keep_the_session() {
+        let (mut a, mut b) = connected();
+
+        for _ in 0..2 * MAX_USED_NONCES {
+            b.on_handshake(&injected_hello(random_nonce()));
+            // The replies go to whoever injected the hello
+            b.take_output();
+        }
+
+        assert!(b.is_authenticated());
+        assert_eq!(b.used.len(), 1);
+        assert_data_passes(&mut a, &mut b);
+        assert_data_passes(&mut b, &mut a);
+    }
+
+    #[test]
+    fn restarted_peer_replaces_the_session() {
+        let (_, mut b) = connected();
+        let mut a = SecureLink::new(key());
+
+        a.on_timer(Instant::now());
+        exchange(&mut a, &mut b);
+
+        assert!(a.is_authenticated() && b.is_authenticated());
+        assert_data_passes(&mut a, &mut b);
+        assert_data_passes(&mut b, &mut a);
+    }
+
+    #[test]
+    fn replayed_handshake_is_ignored() {
+        let mut a = SecureLink::new(key());
+        let mut b = SecureLink::new(key());
+        a.on_timer(Instant::now());
+        let hello = a.take_output();
+        for frame in &hello {
+            b.on_handshake(&frame.payload);
+        }
+        exchange(&mut a, &mut b);
+
+        // The same hello again after a new session replaced it
+        let mut restarted = SecureLink::new(key());
+        restarted.on_timer(Instant::now());
+        exchange(&mut restarted, &mut b);
+        for frame in &hello {
+            b.on_handshake(&frame.payload);
+        }
+
+        assert!(!b.has_output());
+        assert_data_passes(&mut restarted, &mut b);
+    }
+
+    #[test]
+    fn reflected_handshake_is_rejected() {
+        let mut a = SecureLink::new(key());
+        a.on_timer(Instant::now());
+        for frame in a.take_output() {
+            a.on_handshake(&frame.payload);
+        }
+
+        assert!(!a.has_output());
+        assert!(!a.is_authenticated());
+
+        // A confirm only opens with the key of the other direction
+        let mut b = SecureLink::new(key());
+        b.on_timer(Instant::now());
+        for frame in b.take_output() {
+            a.on_handshake(&frame.payload);
+        }
+        for frame in a.take_output() {
+            a.on_handshake(&frame.payload);
+        }
+
+        assert!(!a.is_authenticated());
+    }
+}
@@ -1,192 +1,413 @@
-use std::io::{Read, Write};
-use std::process::exit;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
 use std::time::Duration;
 
-use serialport::TTYPort;
+use serialport::{DataBits, FlowControl, Parity, SerialPort, SerialPortType, StopBits, TTYPort};
+
+use crate::line::LineState;
+use crate::poll::{read_fd, set_nonblocking, write_fd};
+use crate::transport::Transport;
 
 #[derive(Clone)]
 pub struct SerialConnectionSettings {
     pub baud_rate: u32,
-    pub device_path: String,
+    pub device: DeviceSelector,
 }
 
-pub struct DataBlock {
-    pub id: u8,
-    pub data: Vec<u8>,
+/// Which device a serial port is opened from. Looked up again every time the port is reopened,
+/// so a device that comes back under a different name is still found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// A device path. `*` and `?` in the file name match any device in that directory,
+    /// e.g. `/dev/serial/by-id/usb-Klipper_*`.
+    Path(String),
+    /// A USB serial device. Fields left out match any device.
+    Usb {
+        vid: Option<u16>,
+        pid: Option<u16>,
+        serial: Option<String>,
+    },
 }
 
-pub struct SerialPortManager {
-    pub settings: Option<SerialConnectionSettings>,
-    port: TTYPort,
-    index: usize,
+impl DeviceSelector {
+    /// Path of the device to open. The first match in name order wins if several devices match.
+    pub fn resolve(&self) -> io::Result<String> {
+        let mut matches = match self {
+            DeviceSelector::Path(path) => {
+                let path = Path::new(path);
+                let pattern = match path.file_name().and_then(|name| name.to_str()) {
+                    Some(pattern) if pattern.contains(['*', '?']) => pattern,
+                    _ => return Ok(path.display().to_string()),
+                };
+                let dir = path.parent().unwrap_or(Path::new("."));
+
+                let mut matches = vec![];
+                for entry in fs::read_dir(dir)? {
+                    let entry = entry?;
+                    if let Some(name) = entry.file_name().to_str()
+                        && glob_match(pattern, name)
+                    {
+                        matches.push(entry.path().display().to_string());
+                    }
+                }
+                matches
+            }
+            DeviceSelector::Usb { vid, pid, serial } => serialport::available_ports()?
+                .into_iter()
+                .filter(|port| match &port.port_type {
+                    SerialPortType::UsbPort(info) => {
+                        vid.is_none_or(|vid| vid == info.vid)
+                            && pid.is_none_or(|pid| pid == info.pid)
+                            && serial
+                                .as_ref()
+                                .is_none_or(|serial| info.serial_number.as_ref() == Some(serial))
+                    }
+                    _ => false,
+                })
+                .map(|port| port.port_name)
+                .collect(),
+        };
+
+        matches.sort();
+        matches.into_iter().next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No device matches {}", self),
+            )
+        })
+    }
 }
 
-// Multiplexer port -> Serial port
-pub struct SerialConnectionSenderProcessor {
-    pub id: u8,
-    pub port_manager: Arc<Mutex<SerialPortManager>>,
-    pub port_receiver: Receiver<DataBlock>,
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Path(path) => write!(f, "{}", path),
+            DeviceSelector::Usb { vid, pid, serial } => {
+                let id = |id: &Option<u16>| id.map_or("*".to_string(), |id| format!("{:04x}", id));
+                write!(f, "usb {}:{}", id(vid), id(pid))?;
+
+                if let Some(serial) = serial {
+                    write!(f, " serial {}", serial)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
 }
 
-// Serial port -> Multiplexer port
-pub struct SerialConnectionReceiverProcessor {
-    pub id: u8,
-    pub port_manager: Arc<Mutex<SerialPortManager>>,
-    pub write_to_main_bus: Sender<DataBlock>,
+// Shell style wildcards: `*` matches any run of characters, `?` a single one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last `*`: its position in the pattern and the name
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
-pub struct SerialConnectionSender {
-    pub id: u8,
-    pub port_sender: Sender<DataBlock>,
+// Time between attempts to open a device that is not there, doubled up to the maximum
+const REOPEN_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const REOPEN_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// A serial port that is reopened when it fails, e.g. after a USB device was replugged.
+///
+/// Ports created from settings don't have to exist yet, they are opened on first use.
+pub struct SerialPortManager {
+    pub settings: Option<SerialConnectionSettings>,
+    port: Option<TTYPort>,
+    // Last line settings the peer applied, restored after reopening
+    line_state: Option<LineState>,
+    // Failed attempts to open the device since it was lost, and the wait before the next one
+    attempts: u32,
+    backoff: Duration,
 }
 
 impl SerialPortManager {
     pub fn with_settings(settings: SerialConnectionSettings) -> Self {
-        let port = match serialport::new(&settings.device_path, settings.baud_rate)
-            .timeout(Duration::MAX)
-            .open_native()
-        {
-            Ok(port) => port,
-            Err(e) => {
-                eprintln!(
-                    "Failed to open serial port {}: {}",
-                    &settings.device_path, e
-                );
-                exit(4);
-            }
-        };
-
         SerialPortManager {
             settings: Some(settings),
-            port: port,
-            index: 0,
+            port: None,
+            line_state: None,
+            attempts: 0,
+            backoff: REOPEN_BACKOFF_MIN,
         }
     }
 
-    pub fn with_port(port: TTYPort) -> Self {
-        SerialPortManager {
+    pub fn with_port(port: TTYPort) -> io::Result<Self> {
+        set_nonblocking(port.as_raw_fd())?;
+
+        Ok(SerialPortManager {
             settings: None,
-            port: port,
-            index: 0,
-        }
+            port: Some(port),
+            line_state: None,
+            attempts: 0,
+            backoff: REOPEN_BACKOFF_MIN,
+        })
+    }
+
+    fn open(settings: &SerialConnectionSettings) -> io::Result<(String, TTYPort)> {
+        let path = settings.device.resolve()?;
+        let port = serialport::new(&path, settings.baud_rate).open_native()?;
+        set_nonblocking(port.as_raw_fd())?;
+        Ok((path, port))
+    }
+
+    fn port(&mut self) -> io::Result<&mut TTYPort> {
+        self.port
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Serial port is not open"))
+    }
+}
+
+impl Transport for SerialPortManager {
+    fn fd(&self) -> Option<RawFd> {
+        self.port.as_ref().map(|port| port.as_raw_fd())
     }
 
-    pub fn give_port(&mut self) -> TTYPort {
-        #[cfg(debug_assertions)]
-        println!("Giving port with index: {}", self.index);
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        read_fd(self.port()?.as_raw_fd(), buf)
+    }
 
-        if self.index >= 2 {
-            self.generate_new_set_of_ports();
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_fd(self.port()?.as_raw_fd(), buf)
+    }
+
+    fn reopen(&mut self) -> io::Result<Option<Duration>> {
+        let Some(settings) = self.settings.clone() else {
+            // Virtual ports can't be reopened, and their master does not go away
+            return Ok(None);
+        };
+
+        if self.port.take().is_some() {
+            eprintln!("Lost serial port {}, reopening", settings.device);
         }
 
-        let port = self
-            .port
-            .try_clone_native()
-            .expect("Failed to clone serial port");
-        self.index += 1;
-        port
-    }
-
-    fn generate_new_set_of_ports(&mut self) {
-        let settings = self
-            .settings
-            .clone()
-            .expect("Serial connection settings is unavailable, cannot recreate serial port");
-
-        loop {
-            // TODO: Drop old serial port before opening new one.
-            let serial_port = match serialport::new(&settings.device_path, settings.baud_rate)
-                .timeout(Duration::MAX)
-                .open_native()
-            {
-                Ok(port) => port,
-                Err(e) => {
+        let (path, port) = match Self::open(&settings) {
+            Ok(opened) => opened,
+            Err(e) => {
+                if self.attempts == 0 {
                     eprintln!(
-                        "Failed to open serial port {}: {}. Waiting 100ms.",
-                        settings.device_path, e
+                        "Failed to open serial port {}: {}. Retrying until it is available.",
+                        settings.device, e
                     );
-                    std::thread::sleep(Duration::from_millis(100));
-                    continue;
                 }
-            };
 
-            self.port = serial_port;
-            break;
+                self.attempts += 1;
+                let backoff = self.backoff;
+                self.backoff = (backoff * 2).min(REOPEN_BACKOFF_MAX);
+                return Ok(Some(backoff));
+            }
+        };
+
+        if settings.device != DeviceSelector::Path(path.clone()) {
+            println!("Serial port {} is {}", settings.device, path);
+        }
+
+        if self.attempts > 0 {
+            println!(
+                "Opened serial port {} after {} attempts",
+                settings.device,
+                self.attempts + 1
+            );
         }
 
-        self.index = 0;
+        self.port = Some(port);
+        self.attempts = 0;
+        self.backoff = REOPEN_BACKOFF_MIN;
+
+        if let Some(state) = self.line_state
+            && let Err(e) = self.apply_line_state(&state)
+        {
+            eprintln!(
+                "Failed to restore line settings of {}: {}",
+                settings.device, e
+            );
+        }
+
+        Ok(None)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(self.port()?.clear(serialport::ClearBuffer::Input)?)
     }
-}
 
-impl SerialConnectionReceiverProcessor {
-    pub fn process_loop(&self) {
-        let mut read_port = give_port(&self.port_manager);
+    fn unsent_bytes(&mut self) -> io::Result<usize> {
+        Ok(self.port()?.bytes_to_write()? as usize)
+    }
 
-        #[cfg(debug_assertions)]
-        println!("Starting receiver loop for port ID: {}", self.id);
+    // Only virtual ports are watched. The pty master sees the termios the client set on the slave.
+    fn line_state(&mut self) -> io::Result<Option<LineState>> {
+        if self.settings.is_some() {
+            return Ok(None);
+        }
 
-        loop {
-            let mut buffer = [0u8; 255];
-            // TODO: Maybe combine read blocks so we don't spam the buffer with 1 byte read's?
+        // Polled often, so everything comes from a single ioctl
+        let termios = read_termios(self.port()?)?;
+        let baud_rate = termios.c_ospeed;
 
-            let bytes = match read_port.read(&mut buffer) {
-                Ok(bytes) => bytes,
-                Err(e) => {
-                    eprintln!(
-                        "Error reading from serial port {} in receiver loop: {}. Attempting to reconnect...",
-                        self.id, e
-                    );
-                    read_port = give_port(&self.port_manager);
-                    continue;
-                }
-            };
+        let data_bits = match termios.c_cflag & libc::CSIZE {
+            libc::CS5 => DataBits::Five,
+            libc::CS6 => DataBits::Six,
+            libc::CS7 => DataBits::Seven,
+            _ => DataBits::Eight,
+        };
+
+        let parity = match (
+            termios.c_cflag & libc::PARENB != 0,
+            termios.c_cflag & libc::PARODD != 0,
+        ) {
+            (false, _) => Parity::None,
+            (true, true) => Parity::Odd,
+            (true, false) => Parity::Even,
+        };
+
+        let software = libc::IXON | libc::IXOFF;
+        let flow_control = if termios.c_cflag & libc::CRTSCTS != 0 {
+            FlowControl::Hardware
+        } else if termios.c_iflag & software == software {
+            FlowControl::Software
+        } else {
+            FlowControl::None
+        };
+
+        // Ptys have no modem lines. Clients drop DTR by setting the speed to 0 (hang up), and RTS
+        // can't be told apart from it.
+        Ok(Some(LineState {
+            baud_rate,
+            data_bits,
+            parity,
+            stop_bits: if termios.c_cflag & libc::CSTOPB != 0 {
+                StopBits::Two
+            } else {
+                StopBits::One
+            },
+            flow_control,
+            dtr: baud_rate != 0,
+            rts: baud_rate != 0,
+        }))
+    }
+
+    fn apply_line_state(&mut self, state: &LineState) -> io::Result<()> {
+        let Some(settings) = &mut self.settings else {
+            return Ok(());
+        };
 
-            let block = DataBlock {
-                id: self.id,
-                data: buffer[..bytes].to_vec(),
-            };
+        let port = self.port.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "Serial port is not open")
+        })?;
 
-            self.write_to_main_bus
-                .send(block)
-                .expect("Failed to send data block to main bus");
+        // A hang up only drops the modem lines, the port keeps its last speed
+        if state.baud_rate != 0 {
+            port.set_baud_rate(state.baud_rate)?;
+            settings.baud_rate = state.baud_rate;
         }
+
+        port.set_data_bits(state.data_bits)?;
+        port.set_parity(state.parity)?;
+        port.set_stop_bits(state.stop_bits)?;
+        port.set_flow_control(state.flow_control)?;
+        port.write_data_terminal_ready(state.dtr)?;
+        port.write_request_to_send(state.rts)?;
+        self.line_state = Some(*state);
+
+        println!("Applied line settings to {}: {:?}", settings.device, state);
+
+        Ok(())
     }
 }
 
-impl SerialConnectionSenderProcessor {
-    pub fn process_loop(&self) {
-        let mut write_port = give_port(&self.port_manager);
+// serialport asserts that input and output speed match, which clients don't always keep
+fn read_termios(port: &TTYPort) -> io::Result<libc::termios2> {
+    let mut termios = std::mem::MaybeUninit::<libc::termios2>::uninit();
 
-        #[cfg(debug_assertions)]
-        println!("Starting sender loop for port ID: {}", self.id);
+    // SAFETY: TCGETS2 fills in the termios2 struct on success
+    let termios = unsafe {
+        if libc::ioctl(port.as_raw_fd(), libc::TCGETS2, termios.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
 
-        loop {
-            let block = self
-                .port_receiver
-                .recv()
-                .expect("Failed to receive data block");
+        termios.assume_init()
+    };
 
-            loop {
-                if let Err(e) = write_port.write_all(&block.data) {
-                    eprintln!(
-                        "Error writing to serial port {} in sender loop: {}. Attempting to reconnect...",
-                        self.id, e
-                    );
-                    write_port = give_port(&self.port_manager);
-                    continue;
-                }
+    Ok(termios)
+}
 
-                break;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("ttyACM?", "ttyACM0"));
+        assert!(!glob_match("ttyACM?", "ttyACM10"));
+        assert!(glob_match(
+            "usb-Klipper_*",
+            "usb-Klipper_stm32f446xx_1234-if00"
+        ));
+        assert!(glob_match("usb-*-if00", "usb-Klipper_stm32_1234-if00"));
+        assert!(!glob_match("usb-*-if00", "usb-Klipper_stm32_1234-if01"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("exact", "exact"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn exact_path_is_used_as_is() {
+        let device = DeviceSelector::Path("/dev/serial/by-id/not-plugged-in".to_string());
+        assert_eq!(
+            device.resolve().unwrap(),
+            "/dev/serial/by-id/not-plugged-in"
+        );
+    }
+
+    #[test]
+    fn glob_resolves_to_first_match_in_name_order() {
+        let dir = std::env::temp_dir().join(format!("device-selector-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["ttyACM2", "ttyACM1", "ttyUSB0"] {
+            fs::write(dir.join(name), "").unwrap();
         }
+
+        let resolve =
+            |pattern: &str| DeviceSelector::Path(dir.join(pattern).display().to_string()).resolve();
+        let first = resolve("ttyACM*");
+        let missing = resolve("ttyS*");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first.unwrap(), dir.join("ttyACM1").display().to_string());
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
     }
-}
 
-pub fn give_port(port_manager: &Arc<Mutex<SerialPortManager>>) -> TTYPort {
-    port_manager
-        .lock()
-        .expect("Failed to lock port manager")
-        .give_port()
+    #[test]
+    fn usb_selector_display() {
+        let device = DeviceSelector::Usb {
+            vid: Some(0x1d50),
+            pid: None,
+            serial: Some("1234".to_string()),
+        };
+        assert_eq!(device.to_string(), "usb 1d50:* serial 1234");
+    }
 }
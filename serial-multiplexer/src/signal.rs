@@ -0,0 +1,56 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+// Write end of the pipe the signal handler reports signals to
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    // SAFETY: write is async-signal-safe, a failed write only loses the signal
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            (&raw const byte).cast(),
+            1,
+        )
+    };
+}
+
+/// Calls `handler` on a thread of its own for every SIGINT or SIGTERM.
+///
+/// The signals are handled rather than blocked and waited for, the serial port reads unblock all
+/// signals while they wait.
+pub fn on_signal(mut handler: impl FnMut(i32) + Send + 'static) {
+    let mut fds = [0; 2];
+
+    // SAFETY: fds has room for both ends, the handler only uses async-signal-safe calls
+    unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            eprintln!(
+                "Failed to watch for signals, links are not removed on exit: {}",
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+        libc::sigaction(libc::SIGTERM, &action, std::ptr::null_mut());
+    }
+
+    // SAFETY: the read end is owned by this thread from now on
+    let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+
+    std::thread::spawn(move || {
+        let mut signal = [0u8; 1];
+        while signals.read_exact(&mut signal).is_ok() {
+            handler(signal[0] as i32);
+        }
+    });
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Counters of a single channel, or of the multiplexed link itself.
+///
+/// "Sent" and "received" are seen from the multiplexed link: sent bytes were read from the
+/// channel and went to the peer.
+#[derive(Default)]
+pub struct ChannelStats {
+    name: OnceLock<String>,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub frames_sent: AtomicU64,
+    pub frames_received: AtomicU64,
+    /// Data thrown away because the channel's queue was full or its transport was closed
+    pub dropped: AtomicU64,
+    /// Frames sent again in reliable mode
+    pub retransmits: AtomicU64,
+    /// Times data was skipped or state thrown away to get back in sync with the peer
+    pub resyncs: AtomicU64,
+    pub reconnects: AtomicU64,
+    /// Blocks waiting on the main bus
+    pub queue_depth: AtomicU64,
+    // Milliseconds since the Unix epoch, 0 if nothing happened yet
+    last_activity: AtomicU64,
+}
+
+impl ChannelStats {
+    pub fn name(&self) -> Option<&str> {
+        self.name.get().map(String::as_str)
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Bytes read from the multiplexed link, before they are cut into frames
+    pub fn record_read(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn record_frame_received(&self) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retransmits(&self, frames: usize) {
+        self.retransmits.fetch_add(frames as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_resyncs(&self, resyncs: u64) {
+        self.resyncs.fetch_add(resyncs, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_queue_depth(&self, blocks: usize) {
+        self.queue_depth.store(blocks as u64, Ordering::Relaxed);
+    }
+
+    fn touch(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_activity
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since data last went through the channel
+    pub fn idle_for(&self) -> Option<Duration> {
+        let last_activity = self.last_activity.load(Ordering::Relaxed);
+
+        if last_activity == 0 {
+            return None;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Some(now.saturating_sub(Duration::from_millis(last_activity)))
+    }
+
+    fn counters(&self) -> [(&'static str, &'static str, u64); 9] {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        [
+            ("bytes_sent_total", "counter", load(&self.bytes_sent)),
+            (
+                "bytes_received_total",
+                "counter",
+                load(&self.bytes_received),
+            ),
+            ("frames_sent_total", "counter", load(&self.frames_sent)),
+            (
+                "frames_received_total",
+                "counter",
+                load(&self.frames_received),
+            ),
+            ("dropped_total", "counter", load(&self.dropped)),
+            ("retransmits_total", "counter", load(&self.retransmits)),
+            ("resyncs_total", "counter", load(&self.resyncs)),
+            ("reconnects_total", "counter", load(&self.reconnects)),
+            ("queue_depth", "gauge", load(&self.queue_depth)),
+        ]
+    }
+}
+
+/// Statistics of a [`Multiplexer`](crate::Multiplexer), shared with everything that updates them.
+#[derive(Default)]
+pub struct Stats {
+    pub link: ChannelStats,
+    channels: Mutex<BTreeMap<u8, Arc<ChannelStats>>>,
+}
+
+impl Stats {
+    pub fn channel(&self, id: u8) -> Arc<ChannelStats> {
+        self.channels
+            .lock()
+            .expect("Failed to lock stats")
+            .entry(id)
+            .or_default()
+            .clone()
+    }
+
+    /// Names the channel in the output, e.g. after its config entry.
+    pub fn set_name(&self, id: u8, name: &str) {
+        let _ = self.channel(id).name.set(name.to_string());
+    }
+
+    fn channels(&self) -> Vec<(u8, Arc<ChannelStats>)> {
+        self.channels
+            .lock()
+            .expect("Failed to lock stats")
+            .iter()
+            .map(|(id, stats)| (*id, stats.clone()))
+            .collect()
+    }
+
+    /// Human readable table, one line per channel
+    pub fn render_text(&self) -> String {
+        let mut out = format!(
+            "{:<16} {:>12} {:>12} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>6} {:>10}\n",
+            "channel",
+            "bytes out",
+            "bytes in",
+            "frames out",
+            "frames in",
+            "dropped",
+            "retrans",
+            "resyncs",
+            "reconn",
+            "queue",
+            "idle"
+        );
+
+        let rows = std::iter::once(("link".to_string(), &self.link as &ChannelStats));
+        let channels = self.channels();
+        let rows = rows.chain(channels.iter().map(|(id, stats)| {
+            let label = match stats.name() {
+                Some(name) => format!("{} {}", id, name),
+                None => id.to_string(),
+            };
+            (label, stats.as_ref())
+        }));
+
+        for (label, stats) in rows {
+            let [
+                bytes_out,
+                bytes_in,
+                frames_out,
+                frames_in,
+                dropped,
+                retransmits,
+                resyncs,
+                reconnects,
+                queue,
+            ] = stats.counters().map(|(_, _, value)| value);
+
+            let idle = match stats.idle_for() {
+                Some(idle) => format!("{:.1}s", idle.as_secs_f64()),
+                None => "never".to_string(),
+            };
+
+            let _ = writeln!(
+                out,
+                "{:<16} {:>12} {:>12} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>6} {:>10}",
+                label,
+                bytes_out,
+                bytes_in,
+                frames_out,
+                frames_in,
+                dropped,
+                retransmits,
+                resyncs,
+                reconnects,
+                queue,
+                idle
+            );
+        }
+
+        out
+    }
+
+    /// Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        let channels = self.channels();
+        let link = self.link.counters();
+        let counters: Vec<_> = channels
+            .iter()
+            .map(|(id, stats)| {
+                let name = escape_label(stats.name().unwrap_or_default());
+                (id, name, stats.counters())
+            })
+            .collect();
+
+        for (index, (metric, kind, value)) in link.iter().enumerate() {
+            let _ = writeln!(out, "# TYPE serial_multiplexer_{} {}", metric, kind);
+            let _ = writeln!(
+                out,
+                "serial_multiplexer_{}{{channel=\"link\"}} {}",
+                metric, value
+            );
+
+            for (id, name, counters) in &counters {
+                let _ = writeln!(
+                    out,
+                    "serial_multiplexer_{}{{channel=\"{}\",name=\"{}\"}} {}",
+                    metric, id, name, counters[index].2
+                );
+            }
+        }
+
+        let _ = writeln!(out, "# TYPE serial_multiplexer_idle_seconds gauge");
+        for (id, stats) in &channels {
+            if let Some(idle) = stats.idle_for() {
+                let _ = writeln!(
+                    out,
+                    "serial_multiplexer_idle_seconds{{channel=\"{}\",name=\"{}\"}} {:.3}",
+                    id,
+                    escape_label(stats.name().unwrap_or_default()),
+                    idle.as_secs_f64()
+                );
+            }
+        }
+
+        out
+    }
+}
+
+// Backslashes, quotes and newlines must be escaped in Prometheus label values
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Writes the stats table to every client connecting to `listener`. Runs until accepting fails.
+pub fn serve_text(listener: UnixListener, stats: Arc<Stats>) -> io::Result<()> {
+    // Listeners bound for channels don't block, this one has a thread of its own
+    listener.set_nonblocking(false)?;
+
+    loop {
+        let (mut stream, _) = listener.accept()?;
+
+        if let Err(e) = stream.write_all(stats.render_text().as_bytes()) {
+            eprintln!("Failed to send stats: {}", e);
+        }
+    }
+}
+
+// How long a metrics client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Pause after a failed accept, so a lasting error doesn't spin
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Answers every HTTP request on `listener` with the stats in Prometheus format. Never returns.
+pub fn serve_prometheus(listener: TcpListener, stats: Arc<Stats>) {
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            // E.g. out of descriptors, which may pass
+            Err(e) => {
+                eprintln!("Failed to accept metrics connection: {}", e);
+                thread::sleep(ACCEPT_RETRY_INTERVAL);
+                continue;
+            }
+        };
+
+        // A client that never finishes its request would hold up everyone else
+        if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+            eprintln!("Failed to set metrics connection timeout: {}", e);
+            continue;
+        }
+
+        let mut reader = BufReader::new(&stream);
+
+        // Skip the request, every path gets the metrics
+        let mut line = String::new();
+        while reader.read_line(&mut line).is_ok_and(|n| n > 0) && line != "\r\n" {
+            line.clear();
+        }
+
+        let body = stats.render_prometheus();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+
+        if let Err(e) = (&stream).write_all(response.as_bytes()) {
+            eprintln!("Failed to send metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        let stats = Stats::default();
+        stats.set_name(1, "say \"hi\"\\\n");
+        stats.channel(1).record_dropped();
+
+        let out = stats.render_prometheus();
+
+        assert!(out.contains(
+            "serial_multiplexer_dropped_total{channel=\"1\",name=\"say \\\"hi\\\"\\\\\\n\"} 1"
+        ));
+    }
+}
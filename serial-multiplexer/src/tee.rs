@@ -0,0 +1,261 @@
+use std::fs::{File, OpenOptions, remove_file, rename};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::Direction;
+use crate::transport::Transport;
+
+// Lines longer than this are split, so binary data without newlines still shows up
+const MAX_LINE_LEN: usize = 1024;
+
+// Log lines look like
+// 2026-10-19 07:54:01.123 > text
+// with `>` for data sent to the peer and `<` for data received from it. Carriage returns at the
+// end of a line are dropped, other control characters are escaped.
+
+/// Where a channel's traffic is logged and when the log is rotated.
+#[derive(Clone, Debug)]
+pub struct LogSettings {
+    pub path: PathBuf,
+    /// The log is rotated once it grows beyond this
+    pub max_bytes: u64,
+    /// Rotated logs to keep, as `<path>.1` (the newest) to `<path>.<keep>`
+    pub keep: usize,
+}
+
+struct LineLog {
+    settings: LogSettings,
+    file: File,
+    len: u64,
+    // Unfinished line per direction, with the time its first byte was seen
+    partial: [(Vec<u8>, SystemTime); 2],
+}
+
+impl LineLog {
+    fn open(settings: LogSettings) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&settings.path)?;
+
+        Ok(LineLog {
+            len: file.metadata()?.len(),
+            settings,
+            file,
+            partial: [(vec![], UNIX_EPOCH), (vec![], UNIX_EPOCH)],
+        })
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now();
+        let index = direction as usize;
+
+        for &byte in data {
+            let (line, started) = &mut self.partial[index];
+
+            if line.is_empty() {
+                *started = now;
+            }
+
+            if byte != b'\n' {
+                line.push(byte);
+
+                if line.len() < MAX_LINE_LEN {
+                    continue;
+                }
+            }
+
+            let line = std::mem::take(line);
+            let started = *started;
+            self.write_line(direction, &line, started)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_line(
+        &mut self,
+        direction: Direction,
+        line: &[u8],
+        time: SystemTime,
+    ) -> io::Result<()> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let marker = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+
+        let mut text = format!("{} {} ", timestamp(time), marker);
+        for c in String::from_utf8_lossy(line).chars() {
+            if c.is_control() && c != '\t' {
+                text.extend(c.escape_default());
+            } else {
+                text.push(c);
+            }
+        }
+        text.push('\n');
+
+        self.file.write_all(text.as_bytes())?;
+        self.len += text.len() as u64;
+
+        if self.len >= self.settings.max_bytes {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.settings.path;
+
+        if self.settings.keep == 0 {
+            remove_file(path)?;
+        } else {
+            for index in (1..self.settings.keep).rev() {
+                let from = rotated(path, index);
+
+                if from.exists() {
+                    rename(from, rotated(path, index + 1))?;
+                }
+            }
+
+            rename(path, rotated(path, 1))?;
+        }
+
+        self.file = File::create(path)?;
+        self.len = 0;
+        Ok(())
+    }
+
+    // Writes out unfinished lines
+    fn flush(&mut self) -> io::Result<()> {
+        for direction in [Direction::Sent, Direction::Received] {
+            let (line, started) = &mut self.partial[direction as usize];
+
+            if !line.is_empty() {
+                let line = std::mem::take(line);
+                let started = *started;
+                self.write_line(direction, &line, started)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+// Local time with milliseconds, e.g. 2026-10-19 07:54:01.123
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs() as libc::time_t;
+
+    // SAFETY: localtime_r only writes to the tm we pass it
+    let tm = unsafe {
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&seconds, &mut tm);
+        tm
+    };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Copies the traffic of a channel to a line based log and to a read-only mirror.
+///
+/// Neither ever holds up the channel. A mirror that is not read fast enough misses data, and a
+/// log or mirror that fails is turned off with an error message.
+#[derive(Default)]
+pub struct Tee {
+    log: Option<LineLog>,
+    mirror: Option<Box<dyn Transport>>,
+}
+
+impl Tee {
+    pub fn new() -> Self {
+        Tee::default()
+    }
+
+    /// Logs every line sent or received with a timestamp. Appends to an existing log.
+    pub fn with_log(mut self, settings: LogSettings) -> io::Result<Self> {
+        self.log = Some(LineLog::open(settings)?);
+        Ok(self)
+    }
+
+    /// Writes the traffic of both directions to `mirror`, e.g. a pty someone watches the channel on.
+    /// Anything written to the mirror is thrown away.
+    pub fn with_mirror(mut self, mirror: impl Transport + 'static) -> Self {
+        self.mirror = Some(Box::new(mirror));
+        self
+    }
+
+    pub(crate) fn record(&mut self, id: u8, direction: Direction, data: &[u8]) {
+        if let Some(log) = &mut self.log
+            && let Err(e) = log.record(direction, data)
+        {
+            eprintln!(
+                "Failed to write log {} of device {}: {}, no longer logging it",
+                log.settings.path.display(),
+                id,
+                e
+            );
+            self.log = None;
+        }
+
+        if let Some(mirror) = &mut self.mirror
+            && let Err(e) = mirror_write(mirror.as_mut(), data)
+        {
+            eprintln!(
+                "Failed to write mirror of device {}: {}, no longer mirroring it",
+                id, e
+            );
+            self.mirror = None;
+        }
+    }
+}
+
+impl Drop for Tee {
+    fn drop(&mut self) {
+        if let Some(log) = &mut self.log {
+            let _ = log.flush();
+        }
+    }
+}
+
+fn mirror_write(mirror: &mut dyn Transport, mut data: &[u8]) -> io::Result<()> {
+    // The mirror is read-only, drop whatever the watcher typed
+    let mut discard = [0u8; 256];
+    loop {
+        match mirror.read(&mut discard) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    while !data.is_empty() {
+        match mirror.write(data) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(bytes) => data = &data[bytes..],
+            // The watcher doesn't keep up. It misses data instead of slowing down the channel.
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::line::LineState;
+use crate::poll::set_nonblocking;
+
+/// Something bytes can be moved over: the multiplexed link, or the local end of a channel.
+///
+/// Transports are driven by the multiplexer's event loop and must never block. Reads and writes
+/// return `WouldBlock` until the descriptor from [`fd`](Transport::fd) is ready. After an I/O error
+/// the loop calls [`reopen`](Transport::reopen), which gives the transport a chance to reconnect.
+pub trait Transport: Send {
+    /// Descriptor to wait on. `None` while the transport is closed.
+    fn fd(&self) -> Option<RawFd>;
+
+    /// Whether data can flow. A listener waiting for a client has a descriptor, but no connection.
+    fn is_connected(&self) -> bool {
+        self.fd().is_some()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Called when the transport is closed or failed. Returns `None` once it is open again, or how
+    /// long to wait before the next attempt. Returning an error gives up on the transport.
+    fn reopen(&mut self) -> io::Result<Option<Duration>> {
+        Err(stream_closed())
+    }
+
+    /// Discards any received but unread data. Used to regain sync on the plain frame format.
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Bytes written but not yet sent, for transports with an output buffer of their own.
+    fn unsent_bytes(&mut self) -> io::Result<usize> {
+        Ok(0)
+    }
+
+    /// Line settings the local client has set, for transports that are watched for changes.
+    fn line_state(&mut self) -> io::Result<Option<LineState>> {
+        Ok(None)
+    }
+
+    /// Applies line settings the peer's client has set. Ignored by transports without a line.
+    fn apply_line_state(&mut self, _state: &LineState) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Bytes the multiplexed link may hold in its own output buffer. Anything beyond that waits on
+// the main bus, where the scheduler can still put more important data in front of it.
+pub const OUTPUT_LOW_WATER: usize = 64;
+
+/// An already connected stream used as a transport.
+///
+/// The stream cannot be reopened, so once it fails whatever uses it stops.
+pub struct Stream<S: Read + Write + AsRawFd + Send> {
+    stream: Option<S>,
+}
+
+impl<S: Read + Write + AsRawFd + Send> Stream<S> {
+    pub fn new(stream: S) -> io::Result<Self> {
+        set_nonblocking(stream.as_raw_fd())?;
+
+        Ok(Stream {
+            stream: Some(stream),
+        })
+    }
+
+    fn stream(&mut self) -> io::Result<&mut S> {
+        self.stream.as_mut().ok_or_else(stream_closed)
+    }
+}
+
+fn stream_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotConnected,
+        "Stream closed and cannot be reopened",
+    )
+}
+
+impl<S: Read + Write + AsRawFd + Send> Transport for Stream<S> {
+    fn fd(&self) -> Option<RawFd> {
+        self.stream.as_ref().map(|stream| stream.as_raw_fd())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream()?.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream()?.write(buf)
+    }
+
+    fn reopen(&mut self) -> io::Result<Option<Duration>> {
+        self.stream = None;
+        Err(stream_closed())
+    }
+}
+
+/// One end of an in-memory duplex link.
+pub struct Pipe {
+    socket: UnixStream,
+}
+
+impl Pipe {
+    /// Creates two connected ends. Bytes written to one end can be read from the other.
+    pub fn pair() -> io::Result<(Pipe, Pipe)> {
+        let (a, b) = UnixStream::pair()?;
+        Ok((Pipe { socket: a }, Pipe { socket: b }))
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl AsRawFd for Pipe {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
@@ -0,0 +1,446 @@
+//! Two multiplexers talking over pty pairs, with a relay in between that can drop and corrupt bytes.
+
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serial_multiplexer::serial_connection::SerialPortManager;
+use serial_multiplexer::{
+    Channel, CoalesceSettings, LengthEncoding, Multiplexer, PresharedKey, ReliableSettings,
+    ShutdownHandle, Stats, Stream,
+};
+use serialport::TTYPort;
+
+const CHANNELS: [u8; 3] = [1, 2, 7];
+const TIMEOUT: Duration = Duration::from_secs(30);
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+// Small deterministic generator, so a failing run can be repeated
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+// Faults injected by the relay, in bytes per million
+#[derive(Default)]
+struct Faults {
+    drop: AtomicU32,
+    corrupt: AtomicU32,
+}
+
+impl Faults {
+    fn set(&self, drop: u32, corrupt: u32) {
+        self.drop.store(drop, Ordering::Relaxed);
+        self.corrupt.store(corrupt, Ordering::Relaxed);
+    }
+}
+
+// Copies bytes from one pty master to the other, applying the faults
+fn relay(
+    mut from: TTYPort,
+    mut to: TTYPort,
+    faults: Arc<Faults>,
+    stop: Arc<AtomicBool>,
+    seed: u64,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut rng = Rng(seed);
+        let mut buffer = [0u8; 4096];
+
+        while !stop.load(Ordering::Relaxed) {
+            let bytes = match from.read(&mut buffer) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                // The multiplexer on the other side of the pty shut down
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => return,
+                Err(e) => panic!("Relay failed to read: {}", e),
+            };
+
+            let drop = faults.drop.load(Ordering::Relaxed);
+            let corrupt = faults.corrupt.load(Ordering::Relaxed);
+            let mut data = Vec::with_capacity(bytes);
+
+            for &byte in &buffer[..bytes] {
+                if rng.next() % 1_000_000 < drop {
+                    continue;
+                }
+
+                if rng.next() % 1_000_000 < corrupt {
+                    data.push(byte ^ (1 << (rng.next() % 8)));
+                } else {
+                    data.push(byte);
+                }
+            }
+
+            if to.write_all(&data).is_err() {
+                return;
+            }
+        }
+    })
+}
+
+struct Endpoint {
+    shutdown: ShutdownHandle,
+    stats: Arc<Stats>,
+    thread: JoinHandle<()>,
+    // Client ends of the channels, in the order of CHANNELS
+    clients: Vec<UnixStream>,
+}
+
+struct Setup {
+    reliable: bool,
+    large_frames: bool,
+    psk: bool,
+}
+
+// `missing` is a channel of CHANNELS the endpoint doesn't have. Its client is not connected.
+fn endpoint(port: TTYPort, setup: &Setup, missing: Option<u8>) -> Endpoint {
+    let link = SerialPortManager::with_port(port).unwrap();
+    let mut multiplexer = Multiplexer::new(link);
+
+    if setup.reliable {
+        multiplexer = multiplexer.with_reliable(ReliableSettings {
+            window_size: 8,
+            retransmit_timeout: Duration::from_millis(50),
+        });
+    }
+
+    if setup.large_frames {
+        multiplexer = multiplexer.with_length_encoding(LengthEncoding::Varint);
+    }
+
+    if setup.psk {
+        multiplexer = multiplexer.with_psk(PresharedKey::from_hex(KEY).unwrap());
+    }
+
+    let clients = CHANNELS
+        .iter()
+        .map(|&id| {
+            let (client, local) = UnixStream::pair().unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+
+            if missing == Some(id) {
+                return client;
+            }
+
+            let channel =
+                Channel::new(id, Stream::new(local).unwrap()).with_coalesce(CoalesceSettings {
+                    max_bytes: setup.large_frames.then_some(1024),
+                    max_latency: Duration::from_millis(2),
+                });
+            multiplexer.add_channel(channel);
+            client
+        })
+        .collect();
+
+    let shutdown = multiplexer.shutdown_handle();
+    let stats = multiplexer.stats();
+    let thread = thread::spawn(move || multiplexer.run().unwrap());
+
+    Endpoint {
+        shutdown,
+        stats,
+        thread,
+        clients,
+    }
+}
+
+struct Loopback {
+    a: Endpoint,
+    b: Endpoint,
+    faults: Arc<Faults>,
+    stop: Arc<AtomicBool>,
+    relays: Vec<JoinHandle<()>>,
+}
+
+impl Loopback {
+    fn new(setup: Setup) -> Self {
+        Loopback::with_missing(setup, None)
+    }
+
+    // Like new, with channel `missing_on_b` only on one end
+    fn with_missing(setup: Setup, missing_on_b: Option<u8>) -> Self {
+        let (a_master, a_slave) = TTYPort::pair().unwrap();
+        let (b_master, b_slave) = TTYPort::pair().unwrap();
+        let faults = Arc::new(Faults::default());
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let relays = vec![
+            relay(
+                a_master.try_clone_native().unwrap(),
+                b_master.try_clone_native().unwrap(),
+                faults.clone(),
+                stop.clone(),
+                1,
+            ),
+            relay(b_master, a_master, faults.clone(), stop.clone(), 2),
+        ];
+
+        Loopback {
+            a: endpoint(a_slave, &setup, None),
+            b: endpoint(b_slave, &setup, missing_on_b),
+            faults,
+            stop,
+            relays,
+        }
+    }
+
+    fn stop(self) -> (Arc<Stats>, Arc<Stats>) {
+        for endpoint in [&self.a, &self.b] {
+            endpoint.shutdown.shutdown();
+        }
+
+        self.stop.store(true, Ordering::Relaxed);
+        self.a.thread.join().unwrap();
+        self.b.thread.join().unwrap();
+
+        for relay in self.relays {
+            relay.join().unwrap();
+        }
+
+        (self.a.stats, self.b.stats)
+    }
+}
+
+// Reads until `len` bytes arrived or the timeout passed
+fn read_len(client: &mut UnixStream, len: usize, timeout: Duration) -> Vec<u8> {
+    let deadline = Instant::now() + timeout;
+    let mut received = vec![];
+    let mut buffer = [0u8; 4096];
+
+    while received.len() < len && Instant::now() < deadline {
+        match client.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes) => received.extend_from_slice(&buffer[..bytes]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => panic!("Failed to read from channel: {}", e),
+        }
+    }
+
+    received
+}
+
+// Sends random data on every channel in both directions at once and checks it all arrives in order
+fn transfer(loopback: &Loopback, len: usize) {
+    thread::scope(|scope| {
+        let pairs = loopback.a.clients.iter().zip(&loopback.b.clients);
+
+        for (index, (a, b)) in pairs.enumerate() {
+            for (direction, (from, to)) in [(a, b), (b, a)].into_iter().enumerate() {
+                let data = Rng(100 + 2 * index as u64 + direction as u64).bytes(len);
+                let mut from = from.try_clone().unwrap();
+                let mut to = to.try_clone().unwrap();
+
+                let sent = data.clone();
+                scope.spawn(move || {
+                    for chunk in sent.chunks(97) {
+                        from.write_all(chunk).unwrap();
+                    }
+                });
+
+                scope.spawn(move || {
+                    let received = read_len(&mut to, len, TIMEOUT);
+                    let id = CHANNELS[index];
+
+                    assert_eq!(
+                        received.len(),
+                        len,
+                        "Channel {} lost data in direction {}",
+                        id,
+                        direction
+                    );
+                    assert!(
+                        received == data,
+                        "Channel {} delivered corrupted or reordered data in direction {}",
+                        id,
+                        direction
+                    );
+                });
+            }
+        }
+    });
+}
+
+#[test]
+fn clean_link_delivers_everything() {
+    let loopback = Loopback::new(Setup {
+        reliable: false,
+        large_frames: true,
+        psk: false,
+    });
+
+    transfer(&loopback, 200_000);
+    let (a, b) = loopback.stop();
+
+    for stats in [a, b] {
+        assert_eq!(stats.link.resyncs.load(Ordering::Relaxed), 0);
+
+        for id in CHANNELS {
+            assert_eq!(stats.channel(id).dropped.load(Ordering::Relaxed), 0);
+        }
+    }
+}
+
+#[test]
+fn reliable_link_recovers_from_drops_and_corruption() {
+    let loopback = Loopback::new(Setup {
+        reliable: true,
+        large_frames: false,
+        psk: false,
+    });
+
+    loopback.faults.set(200, 500);
+    transfer(&loopback, 50_000);
+    let (a, b) = loopback.stop();
+
+    let resyncs = a.link.resyncs.load(Ordering::Relaxed) + b.link.resyncs.load(Ordering::Relaxed);
+    let retransmits: u64 = CHANNELS
+        .iter()
+        .map(|&id| {
+            a.channel(id).retransmits.load(Ordering::Relaxed)
+                + b.channel(id).retransmits.load(Ordering::Relaxed)
+        })
+        .sum();
+
+    assert!(resyncs > 0, "Corrupted frames were not noticed");
+    assert!(retransmits > 0, "Lost frames were not retransmitted");
+}
+
+#[test]
+fn encrypted_reliable_link_recovers_from_corruption() {
+    let loopback = Loopback::new(Setup {
+        reliable: true,
+        large_frames: true,
+        psk: true,
+    });
+
+    loopback.faults.set(100, 200);
+    transfer(&loopback, 50_000);
+    loopback.stop();
+}
+
+#[test]
+fn plain_link_resyncs_after_corruption() {
+    let loopback = Loopback::new(Setup {
+        reliable: false,
+        large_frames: false,
+        psk: false,
+    });
+
+    // Garbage throws the plain decoder off, until it sees an unknown id and resyncs
+    loopback.faults.set(0, 20_000);
+    let mut rng = Rng(3);
+    let mut a = loopback.a.clients[0].try_clone().unwrap();
+    let mut b = loopback.b.clients[0].try_clone().unwrap();
+
+    let deadline = Instant::now() + TIMEOUT;
+    while loopback.a.stats.link.resyncs.load(Ordering::Relaxed) == 0
+        && loopback.b.stats.link.resyncs.load(Ordering::Relaxed) == 0
+    {
+        assert!(
+            Instant::now() < deadline,
+            "Corruption never caused a resync"
+        );
+        a.write_all(&rng.bytes(200)).unwrap();
+        b.write_all(&rng.bytes(200)).unwrap();
+        read_len(&mut a, usize::MAX, Duration::from_millis(10));
+        read_len(&mut b, usize::MAX, Duration::from_millis(10));
+    }
+
+    // Once the line is clean the link has to come back, at the latest after the next resync
+    loopback.faults.set(0, 0);
+    let marker = b"marker: the link is in sync again";
+    let deadline = Instant::now() + TIMEOUT;
+
+    loop {
+        assert!(Instant::now() < deadline, "Link did not recover");
+        a.write_all(marker).unwrap();
+        let received = read_len(&mut b, usize::MAX, Duration::from_millis(200));
+
+        if received
+            .windows(marker.len())
+            .any(|window| window == marker)
+        {
+            break;
+        }
+    }
+
+    // Throw away the probes and whatever corrupted frames delivered to other channels
+    for client in loopback.a.clients.iter().chain(&loopback.b.clients) {
+        read_len(
+            &mut client.try_clone().unwrap(),
+            usize::MAX,
+            Duration::from_millis(200),
+        );
+    }
+    transfer(&loopback, 20_000);
+    loopback.stop();
+}
+
+#[test]
+fn reliable_link_acknowledges_unknown_channels() {
+    let missing = CHANNELS[2];
+    let loopback = Loopback::with_missing(
+        Setup {
+            reliable: true,
+            large_frames: false,
+            psk: false,
+        },
+        Some(missing),
+    );
+
+    let mut a = loopback.a.clients[2].try_clone().unwrap();
+    for _ in 0..20 {
+        a.write_all(&[0x55; 100]).unwrap();
+    }
+
+    // Long enough for several retransmit timeouts, were the frames not acknowledged
+    thread::sleep(Duration::from_millis(500));
+    let (a, b) = loopback.stop();
+
+    assert_eq!(a.channel(missing).retransmits.load(Ordering::Relaxed), 0);
+    assert!(b.link.dropped.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn reliable_link_holds_back_data_for_a_channel_nobody_reads() {
+    let loopback = Loopback::new(Setup {
+        reliable: true,
+        large_frames: true,
+        psk: false,
+    });
+
+    // More than the socket and the channel's buffer hold together
+    let len = 1_000_000;
+    let data = Rng(7).bytes(len);
+    let mut a = loopback.a.clients[0].try_clone().unwrap();
+    let mut b = loopback.b.clients[0].try_clone().unwrap();
+
+    let sent = data.clone();
+    let writer = thread::spawn(move || a.write_all(&sent).unwrap());
+
+    // Nobody reads until the channel is full and the frames go unacknowledged
+    thread::sleep(Duration::from_secs(2));
+    let received = read_len(&mut b, len, TIMEOUT);
+    writer.join().unwrap();
+    let (a, b) = loopback.stop();
+
+    assert!(received == data, "Channel lost or reordered data");
+    assert_eq!(b.channel(CHANNELS[0]).dropped.load(Ordering::Relaxed), 0);
+    assert!(a.channel(CHANNELS[0]).retransmits.load(Ordering::Relaxed) > 0);
+}